@@ -1,18 +1,24 @@
 use crate::disk_controller::DiskController;
-use crate::graphics::GraphicsHandler;
+use crate::graphics::{self, GraphicsHandler, Scale};
 use crate::mem_manager::MemManager;
-use crate::sound::SoundHandler;
+use crate::recorder::Recorder;
+use crate::sound::{self, SoundHandler};
 use rust_6502::*;
+use rust_apple2::wizard_of_woz::BlankFormat;
 
 use std::cell::RefCell;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::{fs::File, io::Read};
 
 use sdl2::{render::Canvas, render::TextureCreator, video::Window, video::WindowContext, Sdl};
 
 mod settings {
-    pub const CPU_CLK_SPEED: u32 = 1024000;
+    // The 14.31818MHz master clock of the Apple 2 divided by 14
+    pub const CPU_CLK_SPEED: u32 = 1022727;
+
+    // A frame is one full pass of the video scanner, which makes for just over 60 a second
+    pub const FRAME_RATE: u32 = CPU_CLK_SPEED / crate::graphics::CYCLES_PER_FRAME as u32;
     pub const PERIPH_ROM_SZ: usize = 0x100;
     pub const DISK_SLOT: usize = 0x60;
 }
@@ -27,14 +33,41 @@ mod soft_switch {
     pub const INPUT_CLEAR: usize = 0xC010; // Whole page
 }
 
+// Finds the first file name like "screenshot_0001.png" that isn't taken yet
+fn next_free_path(prefix: &str, suffix: &str) -> PathBuf {
+    (1..)
+        .map(|n| PathBuf::from(format!("{prefix}_{n:04}{suffix}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// Which boot ROM (P5) is installed on the Disk II controller card
+#[derive(Clone, Copy, PartialEq)]
+pub enum DiskRom {
+    Sector16,
+    Sector13,
+}
+
 pub struct Apple2<'a> {
     cpu: Cpu6502<'a>,
     mem_manager: &'a Rc<RefCell<MemManager>>,
     gfx_handler: GraphicsHandler<'a>,
     snd_handler: SoundHandler,
     disk_controller: DiskController,
+    disk_rom: DiskRom,
+    disk_rom_forced: bool,
+    recorder: Option<Recorder>,
+    extra_cycles: u32,
+    sample_clock: u32,
 }
 
+const SCREENSHOT_PREFIX: &str = "screenshot";
+const RECORDING_PREFIX: &str = "recording";
+const BLANK_DISK_PREFIX: &str = "blank";
+
+// New disks made during a session go in drive 2, leaving the boot disk alone
+const BLANK_DISK_DRIVE: usize = 2;
+
 pub const KEY_RIGHT: u8 = 0x95;
 pub const KEY_LEFT: u8 = 0x88;
 
@@ -48,9 +81,16 @@ impl<'a> Apple2<'a> {
             .read_exact(&mut self.mem_manager.borrow_mut().memory[address::FW_START..])
             .expect("Failed to read firmware ROM data!");
 
-        // Disk II ROM
-        let mut disc_rom =
-            File::open("roms/firmware/disk2.rom").expect("Failed to open Disk II ROM!");
+        self.load_disk_rom();
+    }
+
+    fn load_disk_rom(&mut self) {
+        // Disk II ROM (the 13-sector version is the older P5A ROM used by DOS 3.2)
+        let rom_path = match self.disk_rom {
+            DiskRom::Sector16 => "roms/firmware/disk2.rom",
+            DiskRom::Sector13 => "roms/firmware/disk2_13.rom",
+        };
+        let mut disc_rom = File::open(rom_path).expect("Failed to open Disk II ROM!");
 
         disc_rom
             .read_exact(
@@ -60,21 +100,28 @@ impl<'a> Apple2<'a> {
             .expect("Failed to read Disk II ROM data!");
     }
 
-    fn handle_soft_sw(&mut self) {
+    fn handle_soft_sw(&mut self, cpu_cycles: u32) {
         /* It would be nice to put this in the memory management module and have soft switches
         checked only when the CPU does a read/write, but would require the memory manager knowing
         about the Apple2 module which causes borrowing problems. I must learn more about lifetimes
         and borrowing rules for future programs... */
         let cycles = self.mem_manager.borrow().get_cycles();
-        for c in &cycles {
+        let mut scanned_cycles = 0;
+        for (i, c) in cycles.iter().enumerate() {
             if c.address >= 0xC090 {
                 self.disk_controller
-                    .handle_soft_sw(c.address, &mut self.mem_manager.borrow_mut().memory);
+                    .handle_soft_sw(c, &mut self.mem_manager.borrow_mut().memory);
             } else if c.address >= 0xC080 {
                 self.mem_manager
                     .borrow_mut()
                     .handle_soft_sw(c.address, &c.ctype);
             } else if c.address >= 0xC050 {
+                // Catch the beam up to the cycle the switch was hit, so the change lands mid-frame
+                let cycle = (i as u32).min(cpu_cycles);
+                self.gfx_handler
+                    .run_scanner(cycle - scanned_cycles, &self.mem_manager.borrow().memory);
+                scanned_cycles = cycle;
+
                 self.gfx_handler.handle_soft_sw(c.address);
             } else if c.address >= 0xC030 {
                 self.snd_handler.handle_soft_sw(c.address);
@@ -82,6 +129,11 @@ impl<'a> Apple2<'a> {
                 self.mem_manager.borrow_mut().memory[address::INPUT_DATA] &= !(1 << 7);
             }
         }
+
+        self.gfx_handler.run_scanner(
+            cpu_cycles - scanned_cycles,
+            &self.mem_manager.borrow().memory,
+        );
     }
 
     pub fn new(
@@ -103,11 +155,19 @@ impl<'a> Apple2<'a> {
             gfx_handler: GraphicsHandler::new(canvas, texture_creator),
             snd_handler: SoundHandler::new(sdl_context),
             disk_controller: DiskController::new(settings::DISK_SLOT),
+            disk_rom: DiskRom::Sector16,
+            disk_rom_forced: false,
+            recorder: None,
+            extra_cycles: 0,
+            sample_clock: 0,
         }
     }
 
     pub fn init(&mut self) {
         self.load_rom();
+        self.mem_manager
+            .borrow_mut()
+            .insert_card(settings::DISK_SLOT >> 4);
 
         self.cpu.reset();
         self.snd_handler.device.resume();
@@ -119,66 +179,257 @@ impl<'a> Apple2<'a> {
         self.disk_controller.reset();
     }
 
-    pub fn insert_disk(&mut self, file_path: &String) {
-        self.disk_controller.load_image(Path::new(file_path));
+    pub fn insert_disk(&mut self, drive: usize, file_path: &String, archive_index: usize) {
+        self.disk_controller
+            .load_image(drive, Path::new(file_path), archive_index);
+
+        self.select_disk_rom(drive);
+    }
+
+    pub fn insert_blank_disk(&mut self, drive: usize, file_path: &String, format: BlankFormat) {
+        self.disk_controller
+            .load_blank_image(drive, Path::new(file_path), format);
+        self.select_disk_rom(drive);
+    }
+
+    // Creates a new blank disk in the current directory and puts it in drive 2
+    pub fn new_blank_disk(&mut self, format: BlankFormat) {
+        let file_path = next_free_path(BLANK_DISK_PREFIX, ".woz");
+        self.insert_blank_disk(
+            BLANK_DISK_DRIVE,
+            &file_path.to_string_lossy().to_string(),
+            format,
+        );
+    }
+
+    /* A 13-sector disk can only be booted by the 13-sector ROM, and a 16-sector disk only by the
+    16-sector ROM, so swap in the one the disk in drive 1 needs (unless one was chosen by hand). */
+    fn select_disk_rom(&mut self, drive: usize) {
+        if drive != 1 || self.disk_rom_forced {
+            return;
+        }
+
+        let disk_rom = match self.disk_controller.is_13_sector(1) {
+            true => DiskRom::Sector13,
+            false => DiskRom::Sector16,
+        };
+
+        if disk_rom != self.disk_rom {
+            match disk_rom {
+                DiskRom::Sector13 => {
+                    println!("13-sector disk inserted, switching to the 13-sector Disk II ROM.")
+                }
+                DiskRom::Sector16 => {
+                    println!("16-sector disk inserted, switching to the 16-sector Disk II ROM.")
+                }
+            }
+
+            self.disk_rom = disk_rom;
+            self.load_disk_rom();
+        }
+    }
+
+    pub fn save_disk(&mut self) {
+        self.disk_controller.save_images();
+    }
+
+    // An explicitly chosen ROM stays installed whatever disks are inserted afterwards
+    pub fn set_disk_rom(&mut self, disk_rom: DiskRom) {
+        self.disk_rom = disk_rom;
+        self.disk_rom_forced = true;
+        self.load_disk_rom();
     }
 
-    pub fn run_frame(&mut self, frame_rate: u32) {
-        let mut frame_cycles = 0;
-        let cycles_per_frame = settings::CPU_CLK_SPEED / frame_rate;
+    pub fn run_frame(&mut self) {
+        /* A frame lasts exactly as long as the video scanner takes to draw it. The last
+        instruction of a frame can run past its end, so those cycles count toward the next one. */
+        let mut frame_cycles = self.extra_cycles;
+        let cycles_per_frame = graphics::CYCLES_PER_FRAME as u32;
 
         /* Sound stuff...
         Sound is hard okay? */
-        let mut sample_cycles = 0;
         let mut speaker_samples: Vec<bool> = Vec::new();
         let mut polarity_change = false;
         let prev_polarity = self.snd_handler.polarity;
-        let cycles_per_sample = settings::CPU_CLK_SPEED / crate::sound::SAMPLE_RATE;
 
         // Tick the CPU for this frame
         while frame_cycles < cycles_per_frame {
+            // Let the memory manager know what the video scanner will fetch during the instruction
+            self.mem_manager.borrow_mut().video_scanner = self.gfx_handler.video_scanner();
+
             let cycles = self.cpu.tick() as u32;
             frame_cycles += cycles;
-            sample_cycles += cycles;
 
-            if sample_cycles >= cycles_per_sample {
+            self.disk_controller.tick(cycles);
+
+            /* The sample rate doesn't divide the clock speed evenly, so keep track of the leftover
+            time to take exactly as many samples as the frame lasts. */
+            self.sample_clock += cycles * sound::SAMPLE_RATE;
+            while self.sample_clock >= settings::CPU_CLK_SPEED {
+                self.sample_clock -= settings::CPU_CLK_SPEED;
                 speaker_samples.push(self.snd_handler.polarity);
-                sample_cycles = 0;
 
                 if self.snd_handler.polarity != prev_polarity {
                     polarity_change = true;
                 }
             }
 
-            self.handle_soft_sw();
+            self.handle_soft_sw(cycles);
             self.mem_manager.borrow_mut().clear_cycles();
         }
 
+        self.extra_cycles = frame_cycles - cycles_per_frame;
+
         /* Feed sound samples from this frame to the sound handler.
-        If the polarity didn't change, don't insert samples so we don't get that buzzing that
-        SDL produces for non-zero samples. */
-        if polarity_change {
-            self.snd_handler.insert_samples(&speaker_samples);
+        If the polarity didn't change, insert silence instead so we don't get that buzzing that
+        SDL produces for non-zero samples. Every frame is fed so the audio can set the pace. */
+        let levels: Vec<f32> = match polarity_change {
+            true => speaker_samples
+                .iter()
+                .map(|s| sound::sample_level(*s))
+                .collect(),
+            false => vec![0.0; speaker_samples.len()],
+        };
+        self.snd_handler.insert_samples(&levels);
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(err) = recorder.add_samples(&levels) {
+                println!("{err}");
+                self.stop_recording();
+            }
         }
 
         self.disk_controller.handle_motor_off_delay();
+        self.disk_controller.handle_host_dirs();
+    }
+
+    // Waits until the sound played so far has nearly run out, so emulation runs at real speed
+    pub fn wait_for_audio(&mut self) {
+        self.snd_handler.wait_for_playback();
+    }
+
+    pub fn draw_frame(&mut self) {
+        self.gfx_handler.handle_gfx(settings::FRAME_RATE);
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            let pixels = self.gfx_handler.native_pixels();
+            if let Err(err) =
+                recorder.add_frame(graphics::DISP_WIDTH, graphics::DISP_HEIGHT, &pixels)
+            {
+                println!("{err}");
+                self.stop_recording();
+            }
+        }
+    }
+
+    pub fn next_monitor(&mut self) {
+        let monitor = self.gfx_handler.next_monitor();
+        println!("Switched to a {} monitor.", monitor.name());
+    }
+
+    pub fn next_palette(&mut self) {
+        let palette = self.gfx_handler.next_palette();
+        println!("Switched to the {palette} palette.");
+    }
+
+    pub fn set_palette(&mut self, palette: &str) {
+        self.gfx_handler.set_palette(palette).unwrap();
+    }
+
+    pub fn load_char_rom(&mut self, file_path: &str) {
+        self.gfx_handler.load_char_rom(Path::new(file_path));
+    }
+
+    pub fn set_lowercase_adapter(&mut self, lowercase_adapter: bool) {
+        self.gfx_handler.set_lowercase_adapter(lowercase_adapter);
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.gfx_handler.set_scale(scale);
+    }
+
+    pub fn next_scale(&mut self) {
+        let scale = self.gfx_handler.next_scale();
+        println!("Display scale set to {}.", scale.name());
+    }
+
+    pub fn toggle_aspect_correct(&mut self) {
+        match self.gfx_handler.toggle_aspect_correct() {
+            true => println!("Display stretched to 4:3."),
+            false => println!("Display shown with square pixels."),
+        }
+    }
+
+    pub fn toggle_scanlines(&mut self) {
+        match self.gfx_handler.toggle_scanlines() {
+            true => println!("Scanlines on."),
+            false => println!("Scanlines off."),
+        }
+    }
+
+    pub fn toggle_blur(&mut self) {
+        match self.gfx_handler.toggle_blur() {
+            true => println!("Blur on."),
+            false => println!("Blur off."),
+        }
     }
 
-    pub fn draw_frame(&mut self, frame_rate: u32) {
-        self.gfx_handler
-            .handle_gfx(frame_rate, &self.mem_manager.borrow().memory);
+    pub fn toggle_fullscreen(&mut self) {
+        self.gfx_handler.toggle_fullscreen();
+    }
+
+    pub fn screenshot(&self, file_path: &Path, scaled: bool) -> Result<(), &'static str> {
+        self.gfx_handler.screenshot(file_path, scaled)
+    }
+
+    // Saves a screenshot under the next free name in the current directory
+    pub fn take_screenshot(&self, scaled: bool) {
+        let file_path = next_free_path(SCREENSHOT_PREFIX, ".png");
+        match self.screenshot(&file_path, scaled) {
+            Ok(()) => println!("Saved screenshot to {}.", file_path.display()),
+            Err(err) => println!("{err}"),
+        }
+    }
+
+    pub fn start_recording(&mut self, dir: &Path) {
+        match Recorder::start(dir, sound::SAMPLE_RATE) {
+            Ok(recorder) => {
+                println!("Recording to {}.", dir.display());
+                self.recorder = Some(recorder);
+            }
+            Err(err) => println!("{err}"),
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            let dir = recorder.dir().to_path_buf();
+            match recorder.stop() {
+                Ok(frames) => println!("Recorded {frames} frames to {}.", dir.display()),
+                Err(err) => println!("{err}"),
+            }
+        }
+    }
+
+    // Starts recording into the next free directory in the current directory, or stops recording
+    pub fn toggle_recording(&mut self) {
+        match self.recorder.is_some() {
+            true => self.stop_recording(),
+            false => self.start_recording(&next_free_path(RECORDING_PREFIX, "")),
+        }
     }
 
     pub fn input_char(&mut self, ascii: u8) {
         self.mem_manager.borrow_mut().memory[address::INPUT_DATA] = ascii;
     }
 
-    pub fn is_valid_key(ascii: u8) -> bool {
+    pub fn is_valid_key(ascii: u8, lowercase: bool) -> bool {
         // 8 = ASCII for backspace, 13 = ASCII for return/enter
-        matches!(ascii, b' '..=b'^' | b'_' | 8 | 13)
+        matches!(ascii, b' '..=b'^' | b'_' | 8 | 13) || (lowercase && matches!(ascii, b'`'..=b'~'))
     }
 
-    pub fn get_shift_ascii(ascii: u8) -> u8 {
+    // The ~ and | characters can only be typed when lowercase is passed through to the Apple 2
+    pub fn get_shift_ascii(ascii: u8, lowercase: bool) -> u8 {
         match ascii {
             b'1' => b'!',
             b'2' => b'@',
@@ -199,6 +450,8 @@ impl<'a> Apple2<'a> {
             b',' => b'<',
             b'.' => b'>',
             b'/' => b'?',
+            b'`' if lowercase => b'~',
+            b'\\' if lowercase => b'|',
             _ => ascii,
         }
     }
@@ -0,0 +1,99 @@
+/*
+Reads disk images out of gzip and zip archives so they can be inserted without extracting first.
+A gzip archive holds a single image, while a zip archive may hold a whole set of them.
+*/
+
+use std::path::Path;
+use std::{fs::File, io::Read};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+const IMAGE_EXTENSIONS: [&str; 8] = ["woz", "dsk", "do", "po", "d13", "nib", "2mg", "2img"];
+
+fn get_ext(file_name: &str) -> String {
+    match Path::new(file_name).extension() {
+        Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+        None => String::new(),
+    }
+}
+
+fn is_disk_image(file_name: &str) -> bool {
+    IMAGE_EXTENSIONS.contains(&get_ext(file_name).as_str())
+}
+
+pub fn is_zip(file_path: &Path) -> bool {
+    get_ext(&file_path.to_string_lossy()) == "zip"
+}
+
+fn open_zip(file_path: &Path) -> Result<ZipArchive<File>, &'static str> {
+    let file = File::open(file_path).expect("Failed to open zip archive!");
+    ZipArchive::new(file).map_err(|_| "File is not a valid zip archive.")
+}
+
+// Returns the index (within the archive) and name of every disk image in a zip archive
+fn find_images(zip: &mut ZipArchive<File>) -> Vec<(usize, String)> {
+    let mut images = Vec::new();
+
+    for i in 0..zip.len() {
+        if let Ok(entry) = zip.by_index(i) {
+            if entry.is_file() && is_disk_image(entry.name()) {
+                images.push((i, entry.name().to_string()));
+            }
+        }
+    }
+
+    images
+}
+
+// Lists the names of the disk images in a zip archive, in the order they can be selected by
+pub fn list_images(file_path: &Path) -> Result<Vec<String>, &'static str> {
+    let mut zip = open_zip(file_path)?;
+    Ok(find_images(&mut zip)
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect())
+}
+
+/* Reads a disk image, decompressing it if necessary, and returns it along with the name of the
+image itself (so its type can be determined by the extension). For zip archives, the index picks
+which of the disk images inside should be read. */
+pub fn read_image(file_path: &Path, index: usize) -> Result<(String, Vec<u8>), &'static str> {
+    let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
+    let mut file_buf = Vec::new();
+
+    match get_ext(&file_name).as_str() {
+        "gz" => {
+            let file = File::open(file_path).expect("Failed to open gzip archive!");
+            GzDecoder::new(file)
+                .read_to_end(&mut file_buf)
+                .map_err(|_| "Failed to decompress gzip archive.")?;
+
+            // The image inside is simply the archive name without the .gz
+            let inner_name = file_path.file_stem().unwrap().to_string_lossy().to_string();
+            Ok((inner_name, file_buf))
+        }
+        "zip" => {
+            let mut zip = open_zip(file_path)?;
+            let images = find_images(&mut zip);
+            let (zip_idx, inner_name) = images
+                .get(index)
+                .ok_or("Zip archive does not contain a disk image at that index.")?
+                .clone();
+
+            zip.by_index(zip_idx)
+                .and_then(|mut entry| Ok(entry.read_to_end(&mut file_buf)?))
+                .map_err(|_| "Failed to decompress zip archive.")?;
+
+            Ok((inner_name, file_buf))
+        }
+        _ => {
+            let mut image = File::open(file_path).expect("Failed to open disk image!");
+            image
+                .read_to_end(&mut file_buf)
+                .expect("Failed to read disk image data!");
+
+            Ok((file_name, file_buf))
+        }
+    }
+}
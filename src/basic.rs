@@ -0,0 +1,177 @@
+/*
+Turns tokenized Applesoft and Integer BASIC programs (as stored on disk) back into listings.
+
+Applesoft Reference: https://www.txbobsc.com/scsc/scdocumentor/D000.html
+Integer BASIC Reference: https://6502disassembly.com/a2-rom/IntegerBASIC.html
+*/
+
+// Applesoft tokens start at 0x80
+const APPLESOFT_TOKENS: [&str; 107] = [
+    "END", "FOR", "NEXT", "DATA", "INPUT", "DEL", "DIM", "READ", "GR", "TEXT", "PR#", "IN#",
+    "CALL", "PLOT", "HLIN", "VLIN", "HGR2", "HGR", "HCOLOR=", "HPLOT", "DRAW", "XDRAW", "HTAB",
+    "HOME", "ROT=", "SCALE=", "SHLOAD", "TRACE", "NOTRACE", "NORMAL", "INVERSE", "FLASH", "COLOR=",
+    "POP", "VTAB", "HIMEM:", "LOMEM:", "ONERR", "RESUME", "RECALL", "STORE", "SPEED=", "LET",
+    "GOTO", "RUN", "IF", "RESTORE", "&", "GOSUB", "RETURN", "REM", "STOP", "ON", "WAIT", "LOAD",
+    "SAVE", "DEF", "POKE", "PRINT", "CONT", "LIST", "CLEAR", "GET", "NEW", "TAB(", "TO", "FN",
+    "SPC(", "THEN", "AT", "NOT", "STEP", "+", "-", "*", "/", "^", "AND", "OR", ">", "=", "<",
+    "SGN", "INT", "ABS", "USR", "FRE", "SCRN(", "PDL", "POS", "SQR", "RND", "LOG", "EXP", "COS",
+    "SIN", "TAN", "ATN", "PEEK", "LEN", "STR$", "VAL", "ASC", "CHR$", "LEFT$", "RIGHT$", "MID$",
+];
+
+// Integer BASIC tokens are below 0x80, and many symbols have several tokens depending on context
+const INTEGER_TOKENS: [&str; 128] = [
+    "HIMEM:", "", "_", ":", "LOAD", "SAVE", "CON", "RUN", "RUN", "DEL", ",", "NEW", "CLR", "AUTO",
+    ",", "MAN", "HIMEM:", "LOMEM:", "+", "-", "*", "/", "=", "#", ">=", ">", "<=", "<>", "<",
+    "AND", "OR", "MOD", "^", "+", "(", ",", "THEN", "THEN", ",", ",", "\"", "\"", "(", "!", "!",
+    "(", "PEEK", "RND", "SGN", "ABS", "PDL", "RNDX", "(", "+", "-", "NOT", "(", "=", "#", "LEN(",
+    "ASC(", "SCRN(", ",", "(", "$", "$", "(", ",", ",", ";", ";", ";", ",", ",", ",", "TEXT", "GR",
+    "CALL", "DIM", "DIM", "TAB", "END", "INPUT", "INPUT", "INPUT", "FOR", "=", "TO", "STEP",
+    "NEXT", ",", "RETURN", "GOSUB", "REM", "LET", "GOTO", "IF", "PRINT", "PRINT", "PRINT", "POKE",
+    ",", "COLOR=", "PLOT", ",", "HLIN", ",", "AT", "VLIN", ",", "AT", "VTAB", "=", "=", ")", ")",
+    "LIST", ",", "LIST", "POP", "NODSP", "DSP", "NOTRACE", "DSP", "DSP", "TRACE", "PR#", "IN#",
+];
+
+mod integer_token {
+    pub const END_OF_LINE: u8 = 0x01;
+    pub const OPEN_QUOTE: u8 = 0x28;
+    pub const CLOSE_QUOTE: u8 = 0x29;
+    pub const REM: u8 = 0x5D;
+}
+
+fn get_bytes_2(buf: &[u8], start: usize) -> u16 {
+    u16::from_le_bytes(buf[start..start + 2].try_into().unwrap())
+}
+
+// Keywords get a space on either side (just like LIST does), symbols don't
+fn push_token(line: &mut String, token: &str) {
+    if token.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        if !line.ends_with(' ') {
+            line.push(' ');
+        }
+        line.push_str(token);
+        line.push(' ');
+    } else {
+        line.push_str(token);
+    }
+}
+
+fn push_char(line: &mut String, byte: u8) {
+    line.push((byte & 0x7F) as char);
+}
+
+pub fn list_applesoft(program: &[u8]) -> String {
+    let mut listing = String::new();
+    let mut pntr = 0;
+
+    // Each line starts with a pointer to the next line (0 at the end) and the line number
+    while pntr + 4 <= program.len() && get_bytes_2(program, pntr) != 0 {
+        let mut line = get_bytes_2(program, pntr + 2).to_string();
+        line.push(' ');
+        pntr += 4;
+
+        while pntr < program.len() && program[pntr] != 0 {
+            let byte = program[pntr];
+            match APPLESOFT_TOKENS.get(byte.wrapping_sub(0x80) as usize) {
+                Some(token) if byte >= 0x80 => push_token(&mut line, token),
+                _ => push_char(&mut line, byte),
+            }
+
+            pntr += 1;
+        }
+        pntr += 1; // Skip the end of line marker
+
+        listing.push_str(line.trim_end());
+        listing.push('\n');
+    }
+
+    listing
+}
+
+pub fn list_integer(program: &[u8]) -> String {
+    let mut listing = String::new();
+    let mut pntr = 0;
+
+    // Each line starts with its length and line number
+    while pntr + 3 <= program.len() && program[pntr] != 0 {
+        let line_end = (pntr + program[pntr] as usize).min(program.len());
+        let mut line = get_bytes_2(program, pntr + 1).to_string();
+        line.push(' ');
+        pntr += 3;
+
+        let mut in_name = false;
+        while pntr < line_end && program[pntr] != integer_token::END_OF_LINE {
+            let byte = program[pntr];
+            pntr += 1;
+
+            match byte {
+                // Digits not part of a variable name start a number, stored as 2 bytes after
+                0xB0..=0xB9 if !in_name && pntr + 2 <= line_end => {
+                    line.push_str(&get_bytes_2(program, pntr).to_string());
+                    pntr += 2;
+                }
+                0x80..=0xFF => {
+                    // Variable names start with a letter, but may also contain digits
+                    in_name =
+                        (0xC1..=0xDA).contains(&byte) || (in_name && (0xB0..=0xB9).contains(&byte));
+                    push_char(&mut line, byte);
+                }
+                integer_token::OPEN_QUOTE => {
+                    in_name = false;
+                    line.push('"');
+                    while pntr < line_end && program[pntr] != integer_token::CLOSE_QUOTE {
+                        push_char(&mut line, program[pntr]);
+                        pntr += 1;
+                    }
+                    line.push('"');
+                    pntr += 1;
+                }
+                integer_token::REM => {
+                    push_token(&mut line, INTEGER_TOKENS[byte as usize]);
+                    while pntr < line_end && program[pntr] != integer_token::END_OF_LINE {
+                        push_char(&mut line, program[pntr]);
+                        pntr += 1;
+                    }
+                }
+                _ => {
+                    in_name = false;
+                    push_token(&mut line, INTEGER_TOKENS[byte as usize]);
+                }
+            }
+        }
+        pntr = line_end;
+
+        listing.push_str(line.trim_end());
+        listing.push('\n');
+    }
+
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applesoft_listing() {
+        // 10 PRINT "HELLO"
+        // 20 GOTO 10
+        let program = [
+            0x0E, 0x08, 0x0A, 0x00, 0xBA, 0x22, 0x48, 0x45, 0x4C, 0x4C, 0x4F, 0x22, 0x00, 0x1A,
+            0x08, 0x14, 0x00, 0xAB, 0x31, 0x30, 0x00, 0x00, 0x00,
+        ];
+
+        assert_eq!(list_applesoft(&program), "10 PRINT \"HELLO\"\n20 GOTO 10\n");
+    }
+
+    #[test]
+    fn integer_listing() {
+        // 10 PRINT "HI"
+        // 20 A1=5
+        let program = [
+            0x09, 0x0A, 0x00, 0x61, 0x28, 0xC8, 0xC9, 0x29, 0x01, 0x0A, 0x14, 0x00, 0xC1, 0xB1,
+            0x71, 0xB5, 0x05, 0x00, 0x01,
+        ];
+
+        assert_eq!(list_integer(&program), "10 PRINT \"HI\"\n20 A1=5\n");
+    }
+}
@@ -0,0 +1,563 @@
+/*
+a2disk works with Apple II disk images without having to start the emulator.
+
+Usage:
+    a2disk convert [OPTIONS] <INPUT> <OUTPUT>
+    a2disk catalog [--index <N>] <IMAGE> [PATH]
+    a2disk extract [--raw] [--index <N>] <IMAGE> <NAME> [HOST_FILE]
+    a2disk inject [--type <TYPE>] [--addr <ADDRESS>] [--lock] <IMAGE> <HOST_FILE> [NAME]
+    a2disk mkdir <IMAGE> <PATH>
+    a2disk format [--name <NAME>] <IMAGE>
+
+The image types are determined by their extensions (woz, dsk/do, po, d13, nib, 2mg/2img), and
+images may also be read from a gzip or zip archive.
+
+Convert options:
+    --index <N>             Which disk image to use from a zip archive (starting at 1)
+    --volume <N>            Volume number written to the address fields (and 2MG header),
+                            not allowed for sector images
+    --write-protect <on|off>
+    --meta <KEY=VALUE>      Sets a WOZ META field, can be given more than once
+    --order <dos|prodos|nib>
+                            What the 2MG image holds (defaults to prodos)
+
+Files can be read from and written to DOS 3.3 and ProDOS disks, where ProDOS files are given by
+their path (such as DIR/FILE). Extracted BASIC programs are detokenized and text files get their
+line endings converted, unless --raw is given. Injected files are binary unless the host file ends
+in .txt, and binary files need a load address. File types are given as a letter on DOS 3.3 disks
+(T, I, A, B, S or R) and by name or number on ProDOS disks (such as BAS or $FC).
+
+Directories can be created on ProDOS disks, and format creates a blank ProDOS volume.
+*/
+
+use std::path::Path;
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+use rust_apple2::archive;
+use rust_apple2::basic;
+use rust_apple2::dos33::{self, Dos33Disk, DosFile, FileType};
+use rust_apple2::dsk2woz::{self, SectorFormat};
+use rust_apple2::prodos::{self, ProDosDisk};
+use rust_apple2::twomg::{self, ImageFormat, TwoMgImage};
+use rust_apple2::wizard_of_woz::WozImage;
+
+const USAGE: &str = "Usage:
+    a2disk convert [--index N] [--volume N] [--write-protect on|off] [--meta KEY=VALUE]...
+                   [--order dos|prodos|nib] <INPUT> <OUTPUT>
+    a2disk catalog [--index N] <IMAGE> [PATH]
+    a2disk extract [--raw] [--index N] <IMAGE> <NAME> [HOST_FILE]
+    a2disk inject [--type TYPE] [--addr ADDRESS] [--lock] <IMAGE> <HOST_FILE> [NAME]
+    a2disk mkdir <IMAGE> <PATH>
+    a2disk format [--name NAME] <IMAGE>";
+
+// Options that don't take a value
+const SWITCHES: [&str; 2] = ["--raw", "--lock"];
+
+// What goes into the header of a 2MG image
+struct TwoMgOptions {
+    order: ImageFormat,
+    volume: Option<u8>,
+    comment: Option<String>,
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("a2disk: {msg}");
+    std::process::exit(1);
+}
+
+fn get_ext(file_path: &str) -> String {
+    match Path::new(file_path).extension() {
+        Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+        None => String::new(),
+    }
+}
+
+// Splits the arguments into options (along with their values) and everything else
+fn split_args(mut args: impl Iterator<Item = String>) -> (Vec<(String, String)>, Vec<String>) {
+    let mut options = Vec::new();
+    let mut files = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if SWITCHES.contains(&arg.as_str()) {
+            options.push((arg, String::new()));
+        } else if arg.starts_with("--") {
+            let value = args.next().unwrap_or_else(|| fail(USAGE));
+            options.push((arg, value));
+        } else {
+            files.push(arg);
+        }
+    }
+
+    (options, files)
+}
+
+fn parse_index(value: &str) -> usize {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => n - 1,
+        _ => fail("Archive index must be a number starting at 1."),
+    }
+}
+
+// Addresses can be given in decimal, or in hex starting with $ or 0x
+fn parse_address(value: &str) -> u16 {
+    let address = match value.strip_prefix('$').or(value.strip_prefix("0x")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    address.unwrap_or_else(|_| fail("Address must be a number from 0 to $FFFF."))
+}
+
+fn load_image(file_path: &str, archive_index: usize) -> WozImage {
+    WozImage::new(Path::new(file_path), archive_index).unwrap_or_else(|e| fail(e))
+}
+
+// Builds the contents of an image file of the given type
+fn build_image(image: &WozImage, ext: &str, two_mg: &TwoMgOptions) -> Vec<u8> {
+    match ext {
+        "woz" => Ok(image.to_woz()),
+        "dsk" | "do" => image.to_sectors(SectorFormat::Dos33),
+        "po" => image.to_sectors(SectorFormat::ProDos),
+        "d13" => image.to_sectors(SectorFormat::Dos32),
+        "nib" => image.to_nib(),
+        "2mg" | "2img" => {
+            let data = match two_mg.order {
+                ImageFormat::Dos33 => image.to_sectors(SectorFormat::Dos33),
+                ImageFormat::ProDos => image.to_sectors(SectorFormat::ProDos),
+                ImageFormat::Nib => image.to_nib(),
+            }
+            .unwrap_or_else(|e| fail(e));
+
+            Ok(twomg::build(&TwoMgImage {
+                format: two_mg.order,
+                volume: two_mg.volume,
+                locked: image.write_protected,
+                comment: two_mg.comment.clone(),
+                data: &data,
+            }))
+        }
+        _ => fail("Unsupported output image type."),
+    }
+    .unwrap_or_else(|e| fail(e))
+}
+
+fn read_file(file_path: &str) -> Vec<u8> {
+    let mut file = File::open(file_path).unwrap_or_else(|_| fail("Failed to open file."));
+    let mut file_buf = Vec::new();
+    file.read_to_end(&mut file_buf)
+        .expect("Failed to read file data!");
+
+    file_buf
+}
+
+fn write_file(file_path: &str, file_buf: &[u8]) {
+    let mut file = File::create(file_path).expect("Failed to create file!");
+    file.write_all(file_buf)
+        .expect("Failed to write file data!");
+}
+
+// Writes a changed image back to the file it came from, keeping its 2MG header if it has one
+fn save_image(file_path: &str, image: &WozImage) {
+    let ext = get_ext(file_path);
+    if archive::is_zip(Path::new(file_path)) || ext == "gz" {
+        fail("Disk images inside an archive can't be changed.");
+    }
+
+    let mut two_mg = TwoMgOptions {
+        order: ImageFormat::ProDos,
+        volume: None,
+        comment: None,
+    };
+    if ext == "2mg" || ext == "2img" {
+        let file_buf = read_file(file_path);
+        let header = twomg::parse(&file_buf).unwrap_or_else(|e| fail(e));
+        two_mg.order = header.format;
+        two_mg.volume = header.volume;
+        two_mg.comment = header.comment;
+    }
+
+    write_file(file_path, &build_image(image, &ext, &two_mg));
+}
+
+fn convert(args: impl Iterator<Item = String>) {
+    let (options, files) = split_args(args);
+    let [input, output] = <[String; 2]>::try_from(files).unwrap_or_else(|_| fail(USAGE));
+
+    let mut archive_index = 0;
+    let mut volume = None;
+    let mut write_protect = None;
+    let mut meta = Vec::new();
+    let mut order = ImageFormat::ProDos;
+
+    for (option, value) in &options {
+        match option.as_str() {
+            "--index" => archive_index = parse_index(value),
+            "--volume" => {
+                volume = Some(
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| fail("Volume must be 0-255.")),
+                );
+            }
+            "--write-protect" => {
+                write_protect = match value.as_str() {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => fail("Write protect must be either on or off."),
+                };
+            }
+            "--meta" => match value.split_once('=') {
+                Some((key, val)) => meta.push((key, val)),
+                None => fail("META fields must be given as KEY=VALUE."),
+            },
+            "--order" => {
+                order = match value.as_str() {
+                    "dos" => ImageFormat::Dos33,
+                    "prodos" => ImageFormat::ProDos,
+                    "nib" => ImageFormat::Nib,
+                    _ => fail("Order must be one of dos, prodos or nib."),
+                };
+            }
+            _ => fail(USAGE),
+        }
+    }
+
+    let mut image = load_image(&input, archive_index);
+
+    if let Some(write_protect) = write_protect {
+        image.write_protected = write_protect;
+    }
+    for (key, value) in meta {
+        image.set_meta(key, value).unwrap_or_else(|e| fail(e));
+    }
+
+    let ext = get_ext(&output);
+    let is_2mg = matches!(ext.as_str(), "2mg" | "2img");
+
+    // Sector images don't store the volume, so only the nibble images need re-encoding
+    let is_nibble = ext == "woz" || ext == "nib" || (is_2mg && order == ImageFormat::Nib);
+    if volume.is_some() && !is_nibble && !is_2mg {
+        fail("Sector images (dsk, do, po and d13) don't store a volume number.");
+    }
+    if let (Some(volume), true) = (volume, is_nibble) {
+        image.set_volume(volume).unwrap_or_else(|e| fail(e));
+    }
+
+    let two_mg = TwoMgOptions {
+        order,
+        volume,
+        comment: None,
+    };
+    write_file(&output, &build_image(&image, &ext, &two_mg));
+}
+
+// The filesystems that files can be read from and written to
+enum Filesystem {
+    Dos33(Dos33Disk),
+    ProDos(ProDosDisk),
+}
+
+fn open_filesystem(image: &WozImage) -> Filesystem {
+    let dos33 = image
+        .to_sectors(SectorFormat::Dos33)
+        .and_then(Dos33Disk::new);
+    if let Ok(disk) = dos33 {
+        return Filesystem::Dos33(disk);
+    }
+
+    let blocks = image
+        .to_sectors(SectorFormat::ProDos)
+        .unwrap_or_else(|e| fail(e));
+    match ProDosDisk::new(blocks) {
+        Ok(disk) => Filesystem::ProDos(disk),
+        Err(_) => fail("Disk does not contain a DOS 3.3 or ProDOS filesystem."),
+    }
+}
+
+// Puts the changed sectors back onto the disk's tracks, then writes the image back out
+fn save_filesystem(image_path: &str, mut image: WozImage, filesystem: Filesystem) {
+    let (sectors, format, volume) = match filesystem {
+        Filesystem::Dos33(disk) => {
+            let volume = disk.volume();
+            (disk.into_sectors(), SectorFormat::Dos33, volume)
+        }
+        // ProDOS doesn't keep the volume number anywhere, so carry over the one on the disk
+        Filesystem::ProDos(disk) => (
+            disk.into_blocks(),
+            SectorFormat::ProDos,
+            image.volume().unwrap_or(dsk2woz::DEFAULT_VOLUME),
+        ),
+    };
+
+    image.replace_tracks(
+        WozImage::from_sectors(&sectors, format, volume).unwrap_or_else(|e| fail(e)),
+    );
+    save_image(image_path, &image);
+}
+
+fn catalog(args: impl Iterator<Item = String>) {
+    let (options, mut files) = split_args(args);
+    if files.len() == 1 {
+        files.push(String::new());
+    }
+    let [image_path, path] = <[String; 2]>::try_from(files).unwrap_or_else(|_| fail(USAGE));
+
+    let mut archive_index = 0;
+    for (option, value) in &options {
+        match option.as_str() {
+            "--index" => archive_index = parse_index(value),
+            _ => fail(USAGE),
+        }
+    }
+
+    // Just like the CATALOG command of each operating system
+    match open_filesystem(&load_image(&image_path, archive_index)) {
+        Filesystem::Dos33(disk) => {
+            if !path.is_empty() {
+                fail("DOS 3.3 disks don't have directories.");
+            }
+
+            let entries = disk.catalog().unwrap_or_else(|e| fail(e));
+            println!("DISK VOLUME {}\n", disk.volume());
+            for entry in entries {
+                let locked = match entry.locked {
+                    true => '*',
+                    false => ' ',
+                };
+                println!(
+                    "{locked}{} {:03} {}",
+                    entry.file_type.letter(),
+                    entry.num_sectors % 1000,
+                    entry.name
+                );
+            }
+        }
+        Filesystem::ProDos(disk) => {
+            let entries = disk.list_dir(&path).unwrap_or_else(|e| fail(e));
+            let dir_name = path.trim_matches('/').to_ascii_uppercase();
+            match dir_name.is_empty() {
+                true => println!("/{}\n", disk.volume_name()),
+                false => println!("/{}/{dir_name}\n", disk.volume_name()),
+            }
+
+            println!(" NAME            TYPE  BLOCKS  ENDFILE  SUBTYPE");
+            for entry in entries {
+                let locked = match entry.locked {
+                    true => '*',
+                    false => ' ',
+                };
+                println!(
+                    "{locked}{:<15} {:<5} {:>6} {:>8}  ${:04X}",
+                    entry.name,
+                    prodos::type_name(entry.file_type),
+                    entry.blocks_used,
+                    entry.eof,
+                    entry.aux_type
+                );
+            }
+        }
+    }
+}
+
+fn extract(args: impl Iterator<Item = String>) {
+    let (options, mut files) = split_args(args);
+
+    // The host file is named after the file on disk unless told otherwise
+    if files.len() == 2 {
+        let name = files[1].rsplit('/').next().unwrap_or_default().to_string();
+        files.push(name);
+    }
+    let [image_path, name, host_file] =
+        <[String; 3]>::try_from(files).unwrap_or_else(|_| fail(USAGE));
+
+    let mut archive_index = 0;
+    let mut raw = false;
+    for (option, value) in &options {
+        match option.as_str() {
+            "--index" => archive_index = parse_index(value),
+            "--raw" => raw = true,
+            _ => fail(USAGE),
+        }
+    }
+
+    let file_buf = match open_filesystem(&load_image(&image_path, archive_index)) {
+        Filesystem::Dos33(disk) => {
+            let file = disk.read_file(&name).unwrap_or_else(|e| fail(e));
+            if file.file_type == FileType::Binary {
+                println!("Load address: ${:04X}", file.address);
+            }
+
+            match (raw, file.file_type) {
+                (false, FileType::Applesoft) => basic::list_applesoft(&file.data).into_bytes(),
+                (false, FileType::Integer) => basic::list_integer(&file.data).into_bytes(),
+                (false, FileType::Text) => dos33::text_to_host(&file.data),
+                _ => file.data,
+            }
+        }
+        Filesystem::ProDos(disk) => {
+            let (entry, data) = disk.read_file(&name).unwrap_or_else(|e| fail(e));
+            if entry.file_type == prodos::file_type::BIN {
+                println!("Load address: ${:04X}", entry.aux_type);
+            }
+
+            match (raw, entry.file_type) {
+                (false, prodos::file_type::BAS) => basic::list_applesoft(&data).into_bytes(),
+                (false, prodos::file_type::INT) => basic::list_integer(&data).into_bytes(),
+                (false, prodos::file_type::TXT) => prodos::text_to_host(&data),
+                _ => data,
+            }
+        }
+    };
+
+    write_file(&host_file, &file_buf);
+}
+
+fn inject(args: impl Iterator<Item = String>) {
+    let (options, mut files) = split_args(args);
+
+    // The file on disk is named after the host file unless told otherwise
+    if files.len() == 2 {
+        let host_name = Path::new(&files[1]).file_stem().unwrap_or_default();
+        files.push(host_name.to_string_lossy().to_string());
+    }
+    let [image_path, host_file, name] =
+        <[String; 3]>::try_from(files).unwrap_or_else(|_| fail(USAGE));
+
+    let mut type_name = None;
+    let mut address = None;
+    let mut locked = false;
+    for (option, value) in &options {
+        match option.as_str() {
+            "--type" => type_name = Some(value.clone()),
+            "--addr" => address = Some(parse_address(value)),
+            "--lock" => locked = true,
+            _ => fail(USAGE),
+        }
+    }
+
+    let is_text = get_ext(&host_file) == "txt";
+    let host_data = read_file(&host_file);
+    let image = load_image(&image_path, 0);
+    let mut filesystem = open_filesystem(&image);
+
+    match &mut filesystem {
+        Filesystem::Dos33(disk) => {
+            let file_type = match (&type_name, is_text) {
+                (Some(type_name), _) => type_name
+                    .chars()
+                    .next()
+                    .and_then(FileType::from_letter)
+                    .unwrap_or_else(|| fail("File type must be one of T, I, A, B, S or R.")),
+                (None, true) => FileType::Text,
+                (None, false) => FileType::Binary,
+            };
+
+            if file_type == FileType::Binary && address.is_none() {
+                fail("Binary files need a load address (--addr).");
+            }
+
+            let file = DosFile {
+                file_type,
+                address: address.unwrap_or_default(),
+                data: match file_type {
+                    FileType::Text => dos33::text_from_host(&host_data),
+                    _ => host_data,
+                },
+            };
+            disk.write_file(&name, &file, locked)
+                .unwrap_or_else(|e| fail(e));
+        }
+        Filesystem::ProDos(disk) => {
+            let file_type = match (&type_name, is_text) {
+                (Some(type_name), _) => prodos::type_from_name(type_name)
+                    .unwrap_or_else(|| fail("File type must be a name such as BIN or $06.")),
+                (None, true) => prodos::file_type::TXT,
+                (None, false) => prodos::file_type::BIN,
+            };
+
+            // The aux type holds the load address of binary files and BASIC programs
+            let aux_type = match (file_type, address) {
+                (_, Some(address)) => address,
+                (prodos::file_type::BIN, None) => {
+                    fail("Binary files need a load address (--addr).")
+                }
+                (prodos::file_type::BAS, None) => prodos::BAS_ADDRESS,
+                _ => 0,
+            };
+
+            let data = match file_type {
+                prodos::file_type::TXT => prodos::text_from_host(&host_data),
+                _ => host_data,
+            };
+            disk.write_file(&name, file_type, aux_type, &data, locked)
+                .unwrap_or_else(|e| fail(e));
+        }
+    }
+
+    save_filesystem(&image_path, image, filesystem);
+}
+
+fn mkdir(args: impl Iterator<Item = String>) {
+    let (options, files) = split_args(args);
+    let [image_path, path] = <[String; 2]>::try_from(files).unwrap_or_else(|_| fail(USAGE));
+    if !options.is_empty() {
+        fail(USAGE);
+    }
+
+    let image = load_image(&image_path, 0);
+    let mut filesystem = open_filesystem(&image);
+    match &mut filesystem {
+        Filesystem::Dos33(_) => fail("DOS 3.3 disks don't have directories."),
+        Filesystem::ProDos(disk) => disk.create_dir(&path).unwrap_or_else(|e| fail(e)),
+    }
+
+    save_filesystem(&image_path, image, filesystem);
+}
+
+fn format(args: impl Iterator<Item = String>) {
+    let (options, files) = split_args(args);
+    let [image_path] = <[String; 1]>::try_from(files).unwrap_or_else(|_| fail(USAGE));
+
+    let mut volume_name = String::from("BLANK");
+    for (option, value) in &options {
+        match option.as_str() {
+            "--name" => volume_name = value.clone(),
+            _ => fail(USAGE),
+        }
+    }
+
+    let disk = ProDosDisk::format(&volume_name, prodos::FLOPPY_BLOCKS).unwrap_or_else(|e| fail(e));
+    let image = WozImage::from_sectors(
+        &disk.into_blocks(),
+        SectorFormat::ProDos,
+        dsk2woz::DEFAULT_VOLUME,
+    )
+    .unwrap_or_else(|e| fail(e));
+
+    let two_mg = TwoMgOptions {
+        order: ImageFormat::ProDos,
+        volume: None,
+        comment: None,
+    };
+    write_file(
+        &image_path,
+        &build_image(&image, &get_ext(&image_path), &two_mg),
+    );
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+
+    match args.next().as_deref() {
+        Some("convert") => convert(args),
+        Some("catalog") => catalog(args),
+        Some("extract") => extract(args),
+        Some("inject") => inject(args),
+        Some("mkdir") => mkdir(args),
+        Some("format") => format(args),
+        _ => fail(USAGE),
+    }
+}
@@ -1,16 +1,86 @@
 /*
 TODO:
--Handle 2nd disc
--Handle writes
 -Handle proper reset behavior
 */
 
-use crate::wizard_of_woz::WozImage;
+use crate::mem_manager::Cycle;
+use rust_apple2::wizard_of_woz::{self, BlankFormat, WozImage};
 use std::path::Path;
 
 const MAX_TRACK: u8 = 34;
+const MAX_QUARTER_TRACK: u8 = MAX_TRACK * 4;
 const MAX_PHASE: usize = 3;
 
+// Each magnet pulls the head to every fourth half track, so the pattern repeats every 8 quarters
+const QUARTER_TRACKS_PER_PHASE: i32 = 2;
+const QUARTER_TRACKS_PER_CYCLE: i32 = QUARTER_TRACKS_PER_PHASE * (MAX_PHASE as i32 + 1);
+
+// How long the head takes to move by a quarter track (so a whole track takes about 1ms)
+const CYCLES_PER_QUARTER_TRACK: u32 = 256;
+
+const NUM_DRIVES: usize = 2;
+
+// How many frames go by between checks for changes made to mounted host directories
+const HOST_DIR_CHECK_FRAMES: u8 = 60;
+
+// WOZ bit timing is given in 125ns units, and a CPU cycle is (roughly) 1us
+const CLOCK_UNITS_PER_CYCLE: u32 = 8;
+
+// The sequencer is clocked at 2MHz, so it takes two steps for every CPU cycle
+const SEQUENCER_STEPS_PER_CYCLE: u32 = 2;
+const CLOCK_UNITS_PER_STEP: u32 = CLOCK_UNITS_PER_CYCLE / SEQUENCER_STEPS_PER_CYCLE;
+
+/* The Logic State Sequencer (P6) ROM, which decides what happens to the data latch every step.
+Each byte holds the next state in the high nibble and the operation in the low nibble.
+
+It is indexed by the current state, the Q7 (write) and Q6 (load) switches, the high bit of the
+latch (QA), and whether a pulse (a flux transition) is coming from the drive, giving these columns:
+                Q7 off (read)                   Q7 on (write)
+        Q6 off (shift)  Q6 on (load)    Q6 off (shift)  Q6 on (load)
+        QA off  QA on   QA off  QA on   QA off  QA on   QA off  QA on
+Pulse:  1   0   1   0   1   0   1   0   1   0   1   0   1   0   1   0
+
+Reference: Understanding the Apple II, chapter 9
+*/
+#[rustfmt::skip]
+const SEQUENCER_ROM: [u8; 256] = [
+    0x18, 0x18, 0x18, 0x18, 0x0A, 0x0A, 0x0A, 0x0A, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18,
+    0x2D, 0x2D, 0x38, 0x38, 0x0A, 0x0A, 0x0A, 0x0A, 0x28, 0x28, 0x28, 0x28, 0x28, 0x28, 0x28, 0x28,
+    0xD8, 0x38, 0x08, 0x28, 0x0A, 0x0A, 0x0A, 0x0A, 0x39, 0x39, 0x39, 0x39, 0x3B, 0x3B, 0x3B, 0x3B,
+    0xD8, 0x48, 0x48, 0x48, 0x0A, 0x0A, 0x0A, 0x0A, 0x48, 0x48, 0x48, 0x48, 0x48, 0x48, 0x48, 0x48,
+    0xD8, 0x58, 0xD8, 0x58, 0x0A, 0x0A, 0x0A, 0x0A, 0x58, 0x58, 0x58, 0x58, 0x58, 0x58, 0x58, 0x58,
+    0xD8, 0x68, 0xD8, 0x68, 0x0A, 0x0A, 0x0A, 0x0A, 0x68, 0x68, 0x68, 0x68, 0x68, 0x68, 0x68, 0x68,
+    0xD8, 0x78, 0xD8, 0x78, 0x0A, 0x0A, 0x0A, 0x0A, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78,
+    0xD8, 0x88, 0xD8, 0x88, 0x0A, 0x0A, 0x0A, 0x0A, 0x08, 0x08, 0x88, 0x88, 0x08, 0x08, 0x88, 0x88,
+    0xD8, 0x98, 0xD8, 0x98, 0x0A, 0x0A, 0x0A, 0x0A, 0x98, 0x98, 0x98, 0x98, 0x98, 0x98, 0x98, 0x98,
+    0xD8, 0x29, 0xD8, 0xA8, 0x0A, 0x0A, 0x0A, 0x0A, 0xA8, 0xA8, 0xA8, 0xA8, 0xA8, 0xA8, 0xA8, 0xA8,
+    0xCD, 0xBD, 0xD8, 0xB8, 0x0A, 0x0A, 0x0A, 0x0A, 0xB9, 0xB9, 0xB9, 0xB9, 0xBB, 0xBB, 0xBB, 0xBB,
+    0xD9, 0x59, 0xD8, 0xC8, 0x0A, 0x0A, 0x0A, 0x0A, 0xC8, 0xC8, 0xC8, 0xC8, 0xC8, 0xC8, 0xC8, 0xC8,
+    0xD9, 0xD9, 0xD8, 0xA0, 0x0A, 0x0A, 0x0A, 0x0A, 0xD8, 0xD8, 0xD8, 0xD8, 0xD8, 0xD8, 0xD8, 0xD8,
+    0xD8, 0x08, 0xE8, 0xE8, 0x0A, 0x0A, 0x0A, 0x0A, 0xE8, 0xE8, 0xE8, 0xE8, 0xE8, 0xE8, 0xE8, 0xE8,
+    0xFD, 0xFD, 0xF8, 0xF8, 0x0A, 0x0A, 0x0A, 0x0A, 0xF8, 0xF8, 0xF8, 0xF8, 0xF8, 0xF8, 0xF8, 0xF8,
+    0xDD, 0x4D, 0xE0, 0xE0, 0x0A, 0x0A, 0x0A, 0x0A, 0x88, 0x88, 0x08, 0x08, 0x88, 0x88, 0x08, 0x08,
+];
+
+// Operations in the low nibble of a sequencer ROM byte
+mod sequencer_op {
+    pub const CLR: u8 = 0x0; // Clear the latch
+    pub const SL0: u8 = 0x9; // Shift a 0 into the latch
+    pub const SR: u8 = 0xA; // Shift the write protect sense into the top of the latch
+    pub const LD: u8 = 0xB; // Load the latch from the data bus
+    pub const SL1: u8 = 0xD; // Shift a 1 into the latch
+}
+
+// The write signal to the drive is the top bit of the sequencer state
+const WRITE_SIGNAL: u8 = 1 << 3;
+
+// The number of bit cells the drive looks at to decide whether it is reading a real signal
+const HEAD_WINDOW_MASK: u8 = 0x0F;
+
+// Noise read from an empty part of the disk comes out as a 1 roughly 30% of the time
+const NOISE_ONES_PERCENT: u32 = 30;
+const NOISE_SEED: u32 = 0x2545F491;
+
 mod soft_switch {
     const PERIPH_IO_ADDR: usize = 0xC080;
 
@@ -32,20 +102,66 @@ mod soft_switch {
     pub const DISK_WRITE: usize = PERIPH_IO_ADDR + 0xF;
 }
 
+// Each drive has its own head and disk, but shares the rest of the controller with the other
+struct Drive {
+    disk_image: Option<WozImage>,
+    quarter_track: u8,
+    target_quarter_track: u8,
+    bit_pntr: usize,
+    track_bit_count: u32,
+    head_window: u8,
+}
+
+impl Drive {
+    fn new() -> Self {
+        Drive {
+            disk_image: None,
+            quarter_track: 0,
+            target_quarter_track: 0,
+            bit_pntr: 0,
+            track_bit_count: 0,
+            head_window: 0,
+        }
+    }
+
+    /* The bit pointer is measured in bits of the track it was last used on. Tracks can hold
+    different numbers of bits, so when the head reaches another track, scale the pointer to keep
+    the same angle (the disk keeps spinning at the same speed no matter where the head is). */
+    fn align_bit_pntr(&mut self, bit_count: u32) {
+        if self.track_bit_count != bit_count {
+            if self.track_bit_count > 0 {
+                let angle = self.bit_pntr as u64 * bit_count as u64;
+                self.bit_pntr = (angle / self.track_bit_count as u64) as usize;
+            }
+            self.track_bit_count = bit_count;
+        }
+    }
+
+    // Wrap around to simulate disk spinning in circle
+    fn advance_bit_pntr(&mut self) {
+        if self.track_bit_count > 0 {
+            self.bit_pntr = (self.bit_pntr + 1) % self.track_bit_count as usize;
+        }
+    }
+}
+
 pub struct DiskController {
     slot: usize,
     data_reg: u8,
-    half_track: u8,
-    current_phase: usize,
+    data_bus: u8,
+    sequencer_state: u8,
+    flux_transition: bool,
+    noise_state: u32,
     phases: [bool; MAX_PHASE + 1],
-    bit_pntr: usize,
-    reading_byte: bool,
+    step_clock: u32,
+    bit_clock: u32,
     drives_on: bool,
-    current_drive: u8,
+    current_drive: usize,
     write_mode: bool,
     write_sense: bool,
-    disk_image: Option<WozImage>,
+    drives: [Drive; NUM_DRIVES],
     motor_off_delay: u8,
+    host_dir_check: u8,
 }
 
 impl DiskController {
@@ -53,32 +169,116 @@ impl DiskController {
         DiskController {
             slot,
             data_reg: 0,
-            half_track: 0,
-            current_phase: 0,
+            data_bus: 0,
+            sequencer_state: 0,
+            flux_transition: false,
+            noise_state: NOISE_SEED,
             phases: [false; MAX_PHASE + 1],
-            bit_pntr: 0,
-            reading_byte: false,
+            step_clock: 0,
+            bit_clock: 0,
             drives_on: false,
-            current_drive: 1,
+            current_drive: 0,
             write_mode: false,
             write_sense: false,
-            disk_image: None,
+            drives: [Drive::new(), Drive::new()],
             motor_off_delay: 0,
+            host_dir_check: 0,
         }
     }
 
+    fn drive(&self) -> &Drive {
+        &self.drives[self.current_drive]
+    }
+
+    fn drive_mut(&mut self) -> &mut Drive {
+        &mut self.drives[self.current_drive]
+    }
+
     pub fn reset(&mut self) {
         self.phases.fill(false);
-        self.current_phase = 0;
+        self.update_head_target();
         self.data_reg = 0;
+        self.sequencer_state = 0;
+        self.flux_transition = false;
         self.write_mode = false;
         self.write_sense = false;
         self.motor_off_delay = 0;
         self.drives_on = false;
     }
 
-    pub fn load_image(&mut self, image_path: &Path) {
-        self.disk_image = Some(WozImage::new(image_path).unwrap());
+    // Drives are numbered 1 and 2, just like on the real machine
+    pub fn load_image(&mut self, drive: usize, image_path: &Path, archive_index: usize) {
+        // Don't lose anything written to the disk being replaced
+        self.save_image(drive - 1);
+
+        let disk_image = match WozImage::new(image_path, archive_index) {
+            Ok(disk_image) => disk_image,
+            Err(e) => {
+                println!("{e} {} was not inserted.", image_path.display());
+                return;
+            }
+        };
+        disk_image.print_info();
+
+        self.drives[drive - 1].disk_image = Some(disk_image);
+    }
+
+    // Creates a new disk image file and puts it in the drive
+    pub fn load_blank_image(&mut self, drive: usize, image_path: &Path, format: BlankFormat) {
+        self.save_image(drive - 1);
+
+        let disk_image = match WozImage::blank(image_path, format) {
+            Ok(disk_image) => disk_image,
+            Err(e) => {
+                println!("{e} {} was not created.", image_path.display());
+                return;
+            }
+        };
+        println!("Created blank disk {}.", image_path.display());
+
+        self.drives[drive - 1].disk_image = Some(disk_image);
+    }
+
+    pub fn is_13_sector(&self, drive: usize) -> bool {
+        self.drives[drive - 1]
+            .disk_image
+            .as_ref()
+            .is_some_and(|disk_image| disk_image.is_13_sector())
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if !self.drives_on {
+            return;
+        }
+
+        self.move_head(cycles);
+
+        let bit_timing = match &self.drive().disk_image {
+            Some(disk_image) => disk_image.optimal_bit_timing as u32,
+            None => return,
+        };
+
+        /* The disk keeps spinning underneath the head whether or not the CPU is reading it, so
+        move on to the next bit every time a bit cell passes by. A 1 bit sends a pulse to the
+        sequencer, and when writing, a flux transition during the bit cell writes a 1. */
+        for _ in 0..cycles * SEQUENCER_STEPS_PER_CYCLE {
+            let mut pulse = false;
+
+            self.bit_clock += CLOCK_UNITS_PER_STEP;
+            if self.bit_clock >= bit_timing {
+                self.bit_clock -= bit_timing;
+
+                match self.write_mode {
+                    true => {
+                        self.put_next_bit(self.flux_transition as u8);
+                        self.flux_transition = false;
+                    }
+                    false => pulse = self.get_next_bit() == 1,
+                }
+            }
+
+            self.step_sequencer(pulse);
+        }
     }
 
     pub fn handle_motor_off_delay(&mut self) {
@@ -89,15 +289,67 @@ impl DiskController {
 
             if self.motor_off_delay == 0 {
                 self.drives_on = false;
+                self.save_images();
+            }
+        }
+    }
+
+    /* Files changed on the host show up on a mounted directory's disk, but only while the drives
+    are off (so a program never sees the disk change underneath it) and nothing written to the disk
+    is waiting to be saved. This is called every frame cycle. */
+    pub fn handle_host_dirs(&mut self) {
+        self.host_dir_check += 1;
+        if self.host_dir_check < HOST_DIR_CHECK_FRAMES || self.drives_on {
+            return;
+        }
+        self.host_dir_check = 0;
+
+        for (drive_idx, drive) in self.drives.iter_mut().enumerate() {
+            if let Some(disk_image) = drive.disk_image.as_mut().filter(|d| !d.modified) {
+                match disk_image.refresh_host_dir() {
+                    Ok(true) => println!(
+                        "Host directory changed, rebuilt the disk in drive {}.",
+                        drive_idx + 1
+                    ),
+                    Ok(false) => {}
+                    Err(e) => println!("{e} The disk in drive {} was not rebuilt.", drive_idx + 1),
+                }
+            }
+        }
+    }
+
+    fn save_image(&mut self, drive_idx: usize) {
+        if let Some(disk_image) = self.drives[drive_idx].disk_image.as_mut() {
+            if disk_image.modified {
+                if let Err(e) = disk_image.save() {
+                    println!("{e} Changes to the disk will be lost.");
+                    disk_image.modified = false;
+                }
             }
         }
     }
 
-    pub fn handle_soft_sw(&mut self, address: usize, ram: &mut [u8]) {
-        if self.disk_image.is_none() {
+    pub fn save_images(&mut self) {
+        for drive_idx in 0..NUM_DRIVES {
+            self.save_image(drive_idx);
+        }
+    }
+
+    pub fn handle_soft_sw(&mut self, cycle: &Cycle, ram: &mut [u8]) {
+        if self.drives.iter().all(|drive| drive.disk_image.is_none()) {
             return;
         }
 
+        let address = cycle.address;
+
+        /* Whatever the CPU writes to the controller is what gets loaded into the latch. Writes
+        anywhere else (like the language card RAM) never reach the controller. */
+        let in_slot =
+            (soft_switch::PHASE0_OFF..=soft_switch::DISK_WRITE).contains(&(address - self.slot));
+        if cycle.ctype == "write" && in_slot {
+            self.data_bus = cycle.value;
+        }
+
         match address - self.slot {
             // Off
             soft_switch::PHASE0_OFF => {
@@ -121,19 +373,13 @@ impl DiskController {
                 self.read_bit(address, ram);
             }
             soft_switch::SEL_DRIVE1 => {
-                self.current_drive = 1;
+                self.current_drive = 0;
+                self.update_head_target();
                 self.read_bit(address, ram);
             }
             soft_switch::SHIFT_OFF => {
                 self.write_sense = false;
-                if !self.write_mode {
-                    self.read_bit(address, ram);
-                } else {
-                    // TODO: Actually write data to disk image
-                    // Copy data reg to disk byte pointer
-                    // I assume CPU waits for sequencer to shift out bits?
-                    // So can just do it in one go?
-                }
+                self.read_bit(address, ram);
             }
             soft_switch::DISK_READ => {
                 self.write_mode = false;
@@ -158,15 +404,11 @@ impl DiskController {
                 self.motor_off_delay = 0;
             }
             soft_switch::SEL_DRIVE2 => {
-                self.current_drive = 2;
+                self.current_drive = 1;
+                self.update_head_target();
             }
             soft_switch::SHIFT_ON => {
                 self.write_sense = true;
-                if self.write_mode {
-                    self.data_reg = ram[address];
-                } else {
-                    self.data_reg = 0; // Apprently reading this addr clears data register
-                }
             }
             soft_switch::DISK_WRITE => {
                 self.write_mode = true;
@@ -175,113 +417,195 @@ impl DiskController {
         }
     }
 
-    fn step_motor(&mut self, to: usize) {
-        let from = self.current_phase;
-        let ascending = (to > from && to - from < MAX_PHASE) || (to == 0 && from == MAX_PHASE);
-        let descending = (to < from) || (to == MAX_PHASE && from == 0);
-
-        if ascending && self.half_track < MAX_TRACK * 2 {
-            self.half_track += 1;
-        } else if descending && self.half_track > 0 {
-            self.half_track -= 1;
-        }
-
-        self.current_phase = to;
-    }
-
     fn phase_on(&mut self, phase: usize) {
         self.phases[phase] = true;
-
-        // If the current phase is OFF, move here
-        if !self.phases[self.current_phase] {
-            self.step_motor(phase);
-        }
+        self.update_head_target();
     }
 
     fn phase_off(&mut self, phase: usize) {
         self.phases[phase] = false;
+        self.update_head_target();
+    }
 
-        /* If we just turned off the current phase, but there's a neighboring ON phase,
-        then move there */
-        if self.current_phase == phase {
-            let right_phase = match self.current_phase < MAX_PHASE {
-                true => self.current_phase + 1,
-                false => 0,
-            };
-            let left_phase = match self.current_phase > 0 {
-                true => self.current_phase - 1,
-                false => MAX_PHASE,
+    /* Works out where the energized magnets pull the head to from where it is now. Each magnet
+    pulls the head towards the closest half track it sits at, so two neighboring magnets leave the
+    head on the quarter track in between them. A magnet on the opposite side of the head can't pull
+    it either way, and with every magnet off the head just stays where it is. */
+    fn update_head_target(&mut self) {
+        let phases = self.phases;
+        let drive = self.drive_mut();
+        let position = drive.quarter_track as i32;
+
+        let mut pull = 0;
+        let mut num_magnets = 0;
+        for (phase, _) in phases.iter().enumerate().filter(|(_, on)| **on) {
+            let offset = (phase as i32 * QUARTER_TRACKS_PER_PHASE - position)
+                .rem_euclid(QUARTER_TRACKS_PER_CYCLE);
+            let offset = match offset < QUARTER_TRACKS_PER_CYCLE / 2 {
+                true => offset,
+                false => offset - QUARTER_TRACKS_PER_CYCLE,
             };
 
-            if self.phases[right_phase] {
-                self.step_motor(right_phase);
-            } else if self.phases[left_phase] {
-                self.step_motor(left_phase);
+            if offset != -QUARTER_TRACKS_PER_CYCLE / 2 {
+                pull += offset;
+                num_magnets += 1;
             }
         }
+
+        if num_magnets > 0 {
+            let target = position + pull / num_magnets;
+            drive.target_quarter_track = target.clamp(0, MAX_QUARTER_TRACK as i32) as u8;
+        }
+    }
+
+    // The head takes a little while to settle on the track it is being pulled to
+    fn move_head(&mut self, cycles: u32) {
+        if self.drive().quarter_track == self.drive().target_quarter_track {
+            self.step_clock = 0;
+            return;
+        }
+
+        self.step_clock += cycles;
+        while self.step_clock >= CYCLES_PER_QUARTER_TRACK {
+            self.step_clock -= CYCLES_PER_QUARTER_TRACK;
+
+            let drive = self.drive_mut();
+            match drive.quarter_track < drive.target_quarter_track {
+                true => drive.quarter_track += 1,
+                false => drive.quarter_track -= 1,
+            }
+
+            // The magnets pull differently now that the head moved (one may have been opposite it)
+            self.update_head_target();
+
+            let drive = self.drive();
+            if drive.quarter_track == drive.target_quarter_track {
+                self.step_clock = 0;
+                break;
+            }
+        }
+    }
+
+    /* The track under the head, if any. The image's track map says which track is read at each
+    quarter track, and there is nothing but noise where no track is mapped. */
+    fn current_track(&self) -> Option<usize> {
+        let drive = self.drive();
+        let disk_image = drive.disk_image.as_ref()?;
+        match disk_image.track_map[drive.quarter_track as usize] {
+            wizard_of_woz::NO_TRACK => None,
+            track_idx => Some(track_idx as usize),
+        }
     }
 
     fn get_next_bit(&mut self) -> u8 {
-        // Figure out what track we are on
-        let track_idx = (self.half_track / 2) as usize;
-        let track = &(self.disk_image.as_ref().unwrap().tracks[track_idx]);
-        let track_data = &track.data;
+        // Figure out what track we are on (only noise is picked up between tracks)
+        let track_idx = match self.current_track() {
+            Some(track_idx) => track_idx,
+            None => {
+                self.drive_mut().advance_bit_pntr();
+                return self.random_bit();
+            }
+        };
+        let drive = self.drive_mut();
+        let bit_count = drive.disk_image.as_ref().unwrap().tracks[track_idx].bit_count;
+        drive.align_bit_pntr(bit_count);
+        let track_data = &drive.disk_image.as_ref().unwrap().tracks[track_idx].data;
 
         // Then figure out which byte in the track we are on
-        let byte_idx = self.bit_pntr / 8;
+        let byte_idx = drive.bit_pntr / 8;
         let byte = track_data[byte_idx];
 
         // And finally figure out what bit in that byte we are on
-        let bit_on = self.bit_pntr % 8;
+        let bit_on = drive.bit_pntr % 8;
         let bit = (byte >> (7 - bit_on)) & 1;
 
-        // Wrap around to simulate disk spinning in circle
-        self.bit_pntr += 1;
-        self.bit_pntr %= track.bit_count as usize;
+        drive.advance_bit_pntr();
 
-        bit
+        /* The drive's amplifier (the MC3470) keeps turning up its gain while no flux transitions
+        come along, so after a few 0 bits in a row it starts picking up random noise instead. Just
+        like the WOZ reference suggests, keep a window of the last 4 bits and read a random bit
+        whenever the window is empty (this delays every bit by a bit cell, which doesn't matter). */
+        drive.head_window = ((drive.head_window << 1) | bit) & HEAD_WINDOW_MASK;
+        match drive.head_window {
+            0 => self.random_bit(),
+            head_window => (head_window >> 1) & 1,
+        }
     }
 
-    fn load_bit(&mut self) {
-        let mut bit = self.get_next_bit();
+    // A simple xorshift generator is plenty for noise
+    fn random_bit(&mut self) -> u8 {
+        self.noise_state ^= self.noise_state << 13;
+        self.noise_state ^= self.noise_state >> 17;
+        self.noise_state ^= self.noise_state << 5;
+        (self.noise_state % 100 < NOISE_ONES_PERCENT) as u8
+    }
 
-        if !self.reading_byte {
-            /* If we receive a 0, we are in the middle of a 10-bit self-sync byte so keep reading
-            until at the beginning of a valid disk byte */
-            while bit == 0 {
-                bit = self.get_next_bit();
+    fn put_next_bit(&mut self, bit: u8) {
+        // There is no track to write to between tracks, but the disk still spins
+        let track_idx = match self.current_track() {
+            Some(track_idx) => track_idx,
+            None => {
+                self.drive_mut().advance_bit_pntr();
+                return;
             }
-            self.reading_byte = true;
+        };
+        let drive = self.drive_mut();
+        let bit_count = drive.disk_image.as_ref().unwrap().tracks[track_idx].bit_count;
+        drive.align_bit_pntr(bit_count);
+        let disk_image = drive.disk_image.as_mut().unwrap();
+        let track = &mut disk_image.tracks[track_idx];
+
+        // The drive won't write to a write protected disk, but it still spins
+        if !disk_image.write_protected {
+            let byte_idx = drive.bit_pntr / 8;
+            let bit_on = drive.bit_pntr % 8;
+            track.data[byte_idx] &= !(1 << (7 - bit_on));
+            track.data[byte_idx] |= bit << (7 - bit_on);
+            disk_image.modified = true;
         }
 
-        self.data_reg <<= 1;
-        self.data_reg |= bit;
+        drive.advance_bit_pntr();
     }
 
-    fn read_bit(&mut self, address: usize, ram: &mut [u8]) {
-        if !self.drives_on {
-            return;
+    fn step_sequencer(&mut self, pulse: bool) {
+        let idx = (self.sequencer_state << 4)
+            | ((self.write_mode as u8) << 3)
+            | ((self.write_sense as u8) << 2)
+            | ((self.data_reg >> 7) << 1)
+            | (!pulse as u8);
+        let rom_byte = SEQUENCER_ROM[idx as usize];
+
+        match rom_byte & 0x0F {
+            sequencer_op::CLR => self.data_reg = 0,
+            sequencer_op::SL0 => self.data_reg <<= 1,
+            sequencer_op::SL1 => self.data_reg = (self.data_reg << 1) | 1,
+            sequencer_op::SR => {
+                let write_protected = self
+                    .drive()
+                    .disk_image
+                    .as_ref()
+                    .is_some_and(|disk_image| disk_image.write_protected);
+                self.data_reg = (self.data_reg >> 1) | ((write_protected as u8) << 7);
+            }
+            sequencer_op::LD => self.data_reg = self.data_bus,
+            _ => {} // Nothing happens
         }
 
-        if !self.write_mode {
-            // If in write-protect sense mode, return whether or not disk is write protected
-            if self.write_sense {
-                self.data_reg = match self.disk_image.as_ref().unwrap().write_protected {
-                    true => 1 << 7,
-                    false => 0,
-                };
-            } else {
-                self.load_bit();
-            }
+        // The drive flips the magnetic flux on the disk whenever the write signal changes
+        let next_state = rom_byte >> 4;
+        if self.write_mode && (next_state ^ self.sequencer_state) & WRITE_SIGNAL != 0 {
+            self.flux_transition = true;
         }
 
-        // Put the contents of the register on the data bus
-        ram[address] = self.data_reg;
+        self.sequencer_state = next_state;
+    }
 
-        // If the high bit is set, we've finished reading in a disk byte so clear register
-        if self.data_reg & (1 << 7) != 0 {
-            self.data_reg = 0;
-            self.reading_byte = false;
+    fn read_bit(&mut self, address: usize, ram: &mut [u8]) {
+        if !self.drives_on {
+            return;
         }
+
+        // Put the contents of the latch on the data bus (reading it doesn't change it)
+        ram[address] = self.data_reg;
     }
 }
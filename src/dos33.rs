@@ -0,0 +1,697 @@
+/*
+A DOS 3.3 filesystem on top of the raw sectors of a disk (in DOS order, just like a DSK image).
+Can list the catalog, read files and write new ones.
+
+The VTOC (Volume Table of Contents) at track 17, sector 0 points to the chain of catalog sectors,
+and keeps a bitmap of free sectors. Each catalog entry points to a chain of track/sector lists,
+which in turn list every sector of the file's data in order.
+
+Reference: Beneath Apple DOS, chapter 4
+*/
+
+const NUM_TRACKS: usize = 35;
+const SECTORS_PER_TRACK: usize = 16;
+const BYTES_PER_SECTOR: usize = 256;
+const DSK_SIZE: usize = NUM_TRACKS * SECTORS_PER_TRACK * BYTES_PER_SECTOR;
+
+const VTOC_TRACK: u8 = 17;
+const VTOC_SECTOR: u8 = 0;
+
+// The first few tracks are kept for the DOS image, just like INIT does
+const DOS_TRACKS: u8 = 3;
+const DOS_RELEASE: u8 = 3;
+
+// Offsets into the VTOC
+mod vtoc {
+    pub const CATALOG_TRACK: usize = 0x01;
+    pub const CATALOG_SECTOR: usize = 0x02;
+    pub const DOS_RELEASE: usize = 0x03;
+    pub const VOLUME: usize = 0x06;
+    pub const MAX_TS_PAIRS: usize = 0x27;
+    pub const LAST_TRACK: usize = 0x30;
+    pub const DIRECTION: usize = 0x31;
+    pub const NUM_TRACKS: usize = 0x34;
+    pub const NUM_SECTORS: usize = 0x35;
+    pub const BYTES_PER_SECTOR: usize = 0x36;
+    pub const BITMAP: usize = 0x38;
+}
+
+// Offsets into a catalog entry
+mod entry {
+    pub const TS_LIST_TRACK: usize = 0x00;
+    pub const TS_LIST_SECTOR: usize = 0x01;
+    pub const FILE_TYPE: usize = 0x02;
+    pub const NAME: usize = 0x03;
+    pub const LENGTH: usize = 0x21;
+}
+
+const ENTRIES_START: usize = 0x0B;
+const ENTRY_SIZE: usize = 35;
+const ENTRIES_PER_SECTOR: usize = 7;
+const NAME_LEN: usize = 30;
+
+const TS_OFFSET: usize = 0x05;
+const TS_PAIRS_START: usize = 0x0C;
+const TS_PAIRS_PER_SECTOR: usize = 122;
+
+const DELETED: u8 = 0xFF;
+const LOCKED: u8 = 1 << 7;
+
+// A list of track/sector pairs
+type SectorList = Vec<(u8, u8)>;
+
+// The data sectors of a file in order, with None for holes in random access files
+type SectorMap = Vec<Option<(u8, u8)>>;
+
+// Stops a corrupt disk from sending us around a chain of sectors forever
+const MAX_CHAIN: usize = NUM_TRACKS * SECTORS_PER_TRACK;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileType {
+    Text,
+    Integer,
+    Applesoft,
+    Binary,
+    S,
+    Relocatable,
+    NewA,
+    NewB,
+}
+
+impl FileType {
+    fn from_code(code: u8) -> Self {
+        match code & !LOCKED {
+            0x00 => FileType::Text,
+            0x01 => FileType::Integer,
+            0x02 => FileType::Applesoft,
+            0x04 => FileType::Binary,
+            0x08 => FileType::S,
+            0x10 => FileType::Relocatable,
+            0x20 => FileType::NewA,
+            _ => FileType::NewB,
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            FileType::Text => 0x00,
+            FileType::Integer => 0x01,
+            FileType::Applesoft => 0x02,
+            FileType::Binary => 0x04,
+            FileType::S => 0x08,
+            FileType::Relocatable => 0x10,
+            FileType::NewA => 0x20,
+            FileType::NewB => 0x40,
+        }
+    }
+
+    // The letter shown by CATALOG
+    pub fn letter(&self) -> char {
+        match self {
+            FileType::Text => 'T',
+            FileType::Integer => 'I',
+            FileType::Applesoft => 'A',
+            FileType::Binary => 'B',
+            FileType::S => 'S',
+            FileType::Relocatable => 'R',
+            FileType::NewA => 'a',
+            FileType::NewB => 'b',
+        }
+    }
+
+    pub fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'T' => Some(FileType::Text),
+            'I' => Some(FileType::Integer),
+            'A' => Some(FileType::Applesoft),
+            'B' => Some(FileType::Binary),
+            'S' => Some(FileType::S),
+            'R' => Some(FileType::Relocatable),
+            'a' => Some(FileType::NewA),
+            'b' => Some(FileType::NewB),
+            _ => None,
+        }
+    }
+}
+
+pub struct CatalogEntry {
+    pub name: String,
+    pub file_type: FileType,
+    pub locked: bool,
+    pub num_sectors: u16,
+    ts_list: (u8, u8),
+    entry_idx: usize,
+}
+
+/* The contents of a file without the header DOS puts in front of it. The address is only used by
+binary files, which store where they get loaded into memory. */
+pub struct DosFile {
+    pub file_type: FileType,
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+pub struct Dos33Disk {
+    dsk: Vec<u8>,
+}
+
+fn get_bytes_2(buf: &[u8], start: usize) -> u16 {
+    u16::from_le_bytes(buf[start..start + 2].try_into().unwrap())
+}
+
+fn sector_idx(track: u8, sector: u8) -> usize {
+    (track as usize * SECTORS_PER_TRACK + sector as usize) * BYTES_PER_SECTOR
+}
+
+fn check_ts(track: u8, sector: u8) -> Result<(u8, u8), &'static str> {
+    match (track as usize) < NUM_TRACKS && (sector as usize) < SECTORS_PER_TRACK {
+        true => Ok((track, sector)),
+        false => Err("Disk contains an invalid track/sector link."),
+    }
+}
+
+// Names are stored in uppercase with the high bit set, and padded out with spaces
+fn encode_name(name: &str) -> Result<[u8; NAME_LEN], &'static str> {
+    let name = name.to_ascii_uppercase();
+    if name.is_empty() || name.len() > NAME_LEN || !name.is_ascii() {
+        return Err("File names must be 1 to 30 ASCII characters.");
+    }
+
+    let mut encoded = [b' ' | 0x80; NAME_LEN];
+    for (i, c) in name.bytes().enumerate() {
+        encoded[i] = c | 0x80;
+    }
+
+    Ok(encoded)
+}
+
+fn decode_name(encoded: &[u8]) -> String {
+    let name: String = encoded.iter().map(|c| (c & 0x7F) as char).collect();
+    name.trim_end().to_string()
+}
+
+// Text files are stored with the high bit set and carriage returns ending each line
+pub fn text_to_host(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .map(|c| match c & 0x7F {
+            b'\r' => b'\n',
+            c => c,
+        })
+        .collect()
+}
+
+pub fn text_from_host(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .filter(|c| **c != 0)
+        .map(|c| match c {
+            b'\n' => b'\r' | 0x80,
+            c => c | 0x80,
+        })
+        .collect()
+}
+
+impl Dos33Disk {
+    pub fn new(dsk: Vec<u8>) -> Result<Self, &'static str> {
+        if dsk.len() < DSK_SIZE {
+            return Err("Disk image is too small.");
+        }
+
+        let disk = Dos33Disk { dsk };
+        let vtoc = disk.sector(VTOC_TRACK, VTOC_SECTOR);
+        if vtoc[vtoc::MAX_TS_PAIRS] as usize != TS_PAIRS_PER_SECTOR
+            || vtoc[vtoc::NUM_TRACKS] as usize != NUM_TRACKS
+            || vtoc[vtoc::NUM_SECTORS] as usize != SECTORS_PER_TRACK
+        {
+            return Err("Disk does not contain a DOS 3.3 filesystem.");
+        }
+
+        Ok(disk)
+    }
+
+    // Creates an empty volume with a catalog, like INIT does (but without DOS itself on the disk)
+    pub fn format(volume: u8) -> Self {
+        let mut disk = Dos33Disk {
+            dsk: vec![0; DSK_SIZE],
+        };
+
+        let vtoc = disk.sector_mut(VTOC_TRACK, VTOC_SECTOR);
+        vtoc[vtoc::CATALOG_TRACK] = VTOC_TRACK;
+        vtoc[vtoc::CATALOG_SECTOR] = SECTORS_PER_TRACK as u8 - 1;
+        vtoc[vtoc::DOS_RELEASE] = DOS_RELEASE;
+        vtoc[vtoc::VOLUME] = volume;
+        vtoc[vtoc::MAX_TS_PAIRS] = TS_PAIRS_PER_SECTOR as u8;
+        vtoc[vtoc::LAST_TRACK] = VTOC_TRACK;
+        vtoc[vtoc::DIRECTION] = 1;
+        vtoc[vtoc::NUM_TRACKS] = NUM_TRACKS as u8;
+        vtoc[vtoc::NUM_SECTORS] = SECTORS_PER_TRACK as u8;
+        vtoc[vtoc::BYTES_PER_SECTOR..vtoc::BYTES_PER_SECTOR + 2]
+            .copy_from_slice(&(BYTES_PER_SECTOR as u16).to_le_bytes());
+
+        // The catalog sectors are chained from the end of the track down to sector 1
+        for sector in 2..SECTORS_PER_TRACK as u8 {
+            let catalog = disk.sector_mut(VTOC_TRACK, sector);
+            catalog[1] = VTOC_TRACK;
+            catalog[2] = sector - 1;
+        }
+
+        for track in (DOS_TRACKS..NUM_TRACKS as u8).filter(|track| *track != VTOC_TRACK) {
+            for sector in 0..SECTORS_PER_TRACK as u8 {
+                disk.set_free(track, sector, true);
+            }
+        }
+
+        disk
+    }
+
+    // Returns the sectors of the disk (in DOS order) including any changes made
+    pub fn into_sectors(self) -> Vec<u8> {
+        self.dsk
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.sector(VTOC_TRACK, VTOC_SECTOR)[vtoc::VOLUME]
+    }
+
+    fn sector(&self, track: u8, sector: u8) -> &[u8] {
+        let idx = sector_idx(track, sector);
+        &self.dsk[idx..idx + BYTES_PER_SECTOR]
+    }
+
+    fn sector_mut(&mut self, track: u8, sector: u8) -> &mut [u8] {
+        let idx = sector_idx(track, sector);
+        &mut self.dsk[idx..idx + BYTES_PER_SECTOR]
+    }
+
+    // Follows a chain of sectors, where each points to the next in bytes 1 and 2
+    fn follow_chain(&self, first: (u8, u8)) -> Result<SectorList, &'static str> {
+        let mut chain = Vec::new();
+        let (mut track, mut sector) = first;
+
+        while track != 0 {
+            if chain.len() >= MAX_CHAIN {
+                return Err("Disk contains a sector chain that never ends.");
+            }
+
+            chain.push(check_ts(track, sector)?);
+            let data = self.sector(track, sector);
+            (track, sector) = (data[1], data[2]);
+        }
+
+        Ok(chain)
+    }
+
+    fn catalog_sectors(&self) -> Result<SectorList, &'static str> {
+        let vtoc = self.sector(VTOC_TRACK, VTOC_SECTOR);
+        self.follow_chain((vtoc[vtoc::CATALOG_TRACK], vtoc[vtoc::CATALOG_SECTOR]))
+    }
+
+    // Byte offsets into the disk of every catalog entry, used or not
+    fn entry_idxs(&self) -> Result<Vec<usize>, &'static str> {
+        Ok(self
+            .catalog_sectors()?
+            .into_iter()
+            .flat_map(|(track, sector)| {
+                (0..ENTRIES_PER_SECTOR)
+                    .map(move |i| sector_idx(track, sector) + ENTRIES_START + i * ENTRY_SIZE)
+            })
+            .collect())
+    }
+
+    pub fn catalog(&self) -> Result<Vec<CatalogEntry>, &'static str> {
+        let mut entries = Vec::new();
+
+        for entry_idx in self.entry_idxs()? {
+            let entry = &self.dsk[entry_idx..entry_idx + ENTRY_SIZE];
+            let ts_track = entry[entry::TS_LIST_TRACK];
+
+            // A track of 0 means this entry has never been used, and 0xFF that it was deleted
+            if ts_track == 0 || ts_track == DELETED {
+                continue;
+            }
+
+            entries.push(CatalogEntry {
+                name: decode_name(&entry[entry::NAME..entry::NAME + NAME_LEN]),
+                file_type: FileType::from_code(entry[entry::FILE_TYPE]),
+                locked: entry[entry::FILE_TYPE] & LOCKED != 0,
+                num_sectors: get_bytes_2(entry, entry::LENGTH),
+                ts_list: (ts_track, entry[entry::TS_LIST_SECTOR]),
+                entry_idx,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn find_entry(&self, name: &str) -> Result<CatalogEntry, &'static str> {
+        let name = decode_name(&encode_name(name)?);
+        self.catalog()?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or("File not found.")
+    }
+
+    // Returns the track/sector lists of a file, followed by its data sectors
+    fn file_sectors(&self, entry: &CatalogEntry) -> Result<(SectorList, SectorMap), &'static str> {
+        let ts_lists = self.follow_chain(entry.ts_list)?;
+        let mut data_sectors = Vec::new();
+
+        for (track, sector) in &ts_lists {
+            let ts_list = self.sector(*track, *sector);
+            for pair in ts_list[TS_PAIRS_START..].chunks(2) {
+                // An empty pair is either past the end of the file or a hole in a random access file
+                data_sectors.push(match pair[0] {
+                    0 => None,
+                    _ => Some(check_ts(pair[0], pair[1])?),
+                });
+            }
+        }
+
+        // Only empty pairs come after the end of the file
+        while data_sectors.last() == Some(&None) {
+            data_sectors.pop();
+        }
+
+        Ok((ts_lists, data_sectors))
+    }
+
+    pub fn read_file(&self, name: &str) -> Result<DosFile, &'static str> {
+        let entry = self.find_entry(name)?;
+        let (_, data_sectors) = self.file_sectors(&entry)?;
+
+        // Holes read back as zeros, just like DOS reads them
+        let mut contents = Vec::new();
+        for data_sector in data_sectors {
+            match data_sector {
+                Some((track, sector)) => contents.extend_from_slice(self.sector(track, sector)),
+                None => contents.extend_from_slice(&[0; BYTES_PER_SECTOR]),
+            }
+        }
+
+        // Strip off the header, which gives the length (and address of binary files)
+        let (address, data) = match entry.file_type {
+            FileType::Binary if contents.len() >= 4 => {
+                let len = get_bytes_2(&contents, 2) as usize;
+                (
+                    get_bytes_2(&contents, 0),
+                    &contents[4..(4 + len).min(contents.len())],
+                )
+            }
+            FileType::Integer | FileType::Applesoft if contents.len() >= 2 => {
+                let len = get_bytes_2(&contents, 0) as usize;
+                (0, &contents[2..(2 + len).min(contents.len())])
+            }
+            // Random access files have zeros between records, so only strip them off the end
+            FileType::Text => {
+                let len = contents.iter().rposition(|c| *c != 0).map_or(0, |i| i + 1);
+                (0, &contents[..len])
+            }
+            _ => (0, &contents[..]),
+        };
+
+        Ok(DosFile {
+            file_type: entry.file_type,
+            address,
+            data: data.to_vec(),
+        })
+    }
+
+    fn bitmap_pos(track: u8, sector: u8) -> (usize, u8) {
+        // Each track has 4 bytes, with sectors 15-8 in the first and 7-0 in the second
+        let byte = vtoc::BITMAP + track as usize * 4 + (1 - sector as usize / 8);
+        (byte, 1 << (sector % 8))
+    }
+
+    fn is_free(&self, track: u8, sector: u8) -> bool {
+        let (byte, mask) = Dos33Disk::bitmap_pos(track, sector);
+        self.sector(VTOC_TRACK, VTOC_SECTOR)[byte] & mask != 0
+    }
+
+    fn set_free(&mut self, track: u8, sector: u8, free: bool) {
+        let (byte, mask) = Dos33Disk::bitmap_pos(track, sector);
+        let vtoc = self.sector_mut(VTOC_TRACK, VTOC_SECTOR);
+        match free {
+            true => vtoc[byte] |= mask,
+            false => vtoc[byte] &= !mask,
+        }
+    }
+
+    // Like DOS, start just past the catalog track and work outwards
+    fn free_sectors(&self) -> SectorList {
+        let tracks = (VTOC_TRACK + 1..NUM_TRACKS as u8).chain((1..VTOC_TRACK).rev());
+        tracks
+            .flat_map(|track| (0..SECTORS_PER_TRACK as u8).rev().map(move |s| (track, s)))
+            .filter(|(track, sector)| self.is_free(*track, *sector))
+            .collect()
+    }
+
+    pub fn delete_file(&mut self, name: &str) -> Result<(), &'static str> {
+        let entry = self.find_entry(name)?;
+        if entry.locked {
+            return Err("File is locked.");
+        }
+
+        let (ts_lists, data_sectors) = self.file_sectors(&entry)?;
+        for (track, sector) in ts_lists
+            .into_iter()
+            .chain(data_sectors.into_iter().flatten())
+        {
+            self.set_free(track, sector, true);
+        }
+
+        // DOS keeps the old track in the last byte of the name when a file is deleted
+        let idx = entry.entry_idx;
+        self.dsk[idx + entry::NAME + NAME_LEN - 1] = self.dsk[idx + entry::TS_LIST_TRACK];
+        self.dsk[idx + entry::TS_LIST_TRACK] = DELETED;
+
+        Ok(())
+    }
+
+    // Writes a new file, replacing any unlocked file with the same name
+    pub fn write_file(
+        &mut self,
+        name: &str,
+        file: &DosFile,
+        locked: bool,
+    ) -> Result<(), &'static str> {
+        let encoded_name = encode_name(name)?;
+        let existing = self
+            .catalog()?
+            .into_iter()
+            .find(|entry| entry.name == decode_name(&encoded_name));
+
+        // Put the header DOS expects in front of the data
+        let len = match file.file_type {
+            FileType::Binary | FileType::Integer | FileType::Applesoft => {
+                u16::try_from(file.data.len()).map_err(|_| "File is too big.")?
+            }
+            _ => 0,
+        };
+        let len = len.to_le_bytes();
+        let mut contents = match file.file_type {
+            FileType::Binary => [file.address.to_le_bytes(), len].concat(),
+            FileType::Integer | FileType::Applesoft => len.to_vec(),
+            _ => Vec::new(),
+        };
+        contents.extend_from_slice(&file.data);
+
+        /* Make sure there is room in the catalog and on the disk before changing anything. The
+        catalog entry and sectors of a file being replaced can be used for the new one. */
+        let (reusable_sectors, has_entry) = match &existing {
+            Some(entry) if entry.locked => return Err("File is locked."),
+            Some(entry) => {
+                let (ts_lists, data_sectors) = self.file_sectors(entry)?;
+                (ts_lists.len() + data_sectors.iter().flatten().count(), true)
+            }
+            None => (0, false),
+        };
+
+        let has_free_entry = self
+            .entry_idxs()?
+            .into_iter()
+            .any(|idx| matches!(self.dsk[idx + entry::TS_LIST_TRACK], 0 | DELETED));
+        if !has_entry && !has_free_entry {
+            return Err("Disk catalog is full.");
+        }
+
+        let num_data = contents.len().div_ceil(BYTES_PER_SECTOR);
+        let num_ts_lists = num_data.div_ceil(TS_PAIRS_PER_SECTOR).max(1);
+        if self.free_sectors().len() + reusable_sectors < num_data + num_ts_lists {
+            return Err("Disk is full.");
+        }
+
+        if existing.is_some() {
+            self.delete_file(name)?;
+        }
+
+        let entry_idx = self
+            .entry_idxs()?
+            .into_iter()
+            .find(|idx| matches!(self.dsk[idx + entry::TS_LIST_TRACK], 0 | DELETED))
+            .ok_or("Disk catalog is full.")?;
+        let free_sectors = self.free_sectors();
+
+        let (ts_lists, data_sectors) =
+            free_sectors[..num_ts_lists + num_data].split_at(num_ts_lists);
+        for (track, sector) in ts_lists.iter().chain(data_sectors) {
+            self.set_free(*track, *sector, false);
+        }
+
+        for ((track, sector), chunk) in data_sectors.iter().zip(contents.chunks(BYTES_PER_SECTOR)) {
+            let data = self.sector_mut(*track, *sector);
+            data.fill(0);
+            data[..chunk.len()].copy_from_slice(chunk);
+        }
+
+        for (i, (track, sector)) in ts_lists.iter().enumerate() {
+            let next = ts_lists.get(i + 1).copied().unwrap_or((0, 0));
+            let pairs = data_sectors
+                .iter()
+                .skip(i * TS_PAIRS_PER_SECTOR)
+                .take(TS_PAIRS_PER_SECTOR);
+
+            let ts_list = self.sector_mut(*track, *sector);
+            ts_list.fill(0);
+            (ts_list[1], ts_list[2]) = next;
+            let offset = (i * TS_PAIRS_PER_SECTOR) as u16;
+            ts_list[TS_OFFSET..TS_OFFSET + 2].copy_from_slice(&offset.to_le_bytes());
+            for (j, (data_track, data_sector)) in pairs.enumerate() {
+                ts_list[TS_PAIRS_START + j * 2] = *data_track;
+                ts_list[TS_PAIRS_START + j * 2 + 1] = *data_sector;
+            }
+        }
+
+        let num_sectors = (num_ts_lists + num_data) as u16;
+        let entry = &mut self.dsk[entry_idx..entry_idx + ENTRY_SIZE];
+        entry[entry::TS_LIST_TRACK] = ts_lists[0].0;
+        entry[entry::TS_LIST_SECTOR] = ts_lists[0].1;
+        entry[entry::FILE_TYPE] = file.file_type.code() | ((locked as u8) << 7);
+        entry[entry::NAME..entry::NAME + NAME_LEN].copy_from_slice(&encoded_name);
+        entry[entry::LENGTH..entry::LENGTH + 2].copy_from_slice(&num_sectors.to_le_bytes());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(address: u16, len: usize, seed: u8) -> DosFile {
+        DosFile {
+            file_type: FileType::Binary,
+            address,
+            data: (0..len).map(|i| (i as u8).wrapping_add(seed)).collect(),
+        }
+    }
+
+    #[test]
+    fn write_read_delete() {
+        let mut disk = Dos33Disk::format(254);
+        let program = binary(0x0803, 1000, 0);
+        let text = DosFile {
+            file_type: FileType::Text,
+            address: 0,
+            data: text_from_host(b"HELLO\nWORLD\n"),
+        };
+
+        disk.write_file("PROGRAM", &program, false).unwrap();
+        disk.write_file("NOTES", &text, true).unwrap();
+
+        let read = disk.read_file("PROGRAM").unwrap();
+        assert_eq!(read.address, 0x0803);
+        assert_eq!(read.data, program.data);
+        assert_eq!(
+            text_to_host(&disk.read_file("NOTES").unwrap().data),
+            b"HELLO\nWORLD\n"
+        );
+
+        let catalog = disk.catalog().unwrap();
+        assert_eq!(catalog.len(), 2);
+        assert!(catalog[1].locked);
+
+        // Locked files can't be deleted or replaced
+        assert!(disk.delete_file("NOTES").is_err());
+        assert!(disk.write_file("NOTES", &program, false).is_err());
+
+        let free = disk.free_sectors().len();
+        disk.delete_file("PROGRAM").unwrap();
+        assert!(disk.read_file("PROGRAM").is_err());
+        assert!(disk.free_sectors().len() > free);
+
+        // Everything survives the trip through a sector image
+        let disk = Dos33Disk::new(disk.into_sectors()).unwrap();
+        assert_eq!(disk.catalog().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn too_big() {
+        let mut disk = Dos33Disk::format(254);
+        assert_eq!(
+            disk.write_file("BIG", &binary(0x0800, 65536, 0), false)
+                .err(),
+            Some("File is too big.")
+        );
+    }
+
+    #[test]
+    fn random_access_hole() {
+        let mut disk = Dos33Disk::format(254);
+        let text = DosFile {
+            file_type: FileType::Text,
+            address: 0,
+            data: vec![b'A' | 0x80; BYTES_PER_SECTOR * 3],
+        };
+        disk.write_file("RECORDS", &text, false).unwrap();
+
+        // Punch a hole where the second sector of the file was
+        let entry = disk.find_entry("RECORDS").unwrap();
+        let (_, data_sectors) = disk.file_sectors(&entry).unwrap();
+        let (hole_track, hole_sector) = data_sectors[1].unwrap();
+        let (last_track, last_sector) = data_sectors[2].unwrap();
+        let (ts_track, ts_sector) = entry.ts_list;
+        disk.sector_mut(ts_track, ts_sector)[TS_PAIRS_START + 2..TS_PAIRS_START + 4].fill(0);
+        disk.set_free(hole_track, hole_sector, true);
+
+        let data = disk.read_file("RECORDS").unwrap().data;
+        assert_eq!(data.len(), BYTES_PER_SECTOR * 3);
+        assert!(data[BYTES_PER_SECTOR..BYTES_PER_SECTOR * 2]
+            .iter()
+            .all(|&c| c == 0));
+        assert_eq!(data[BYTES_PER_SECTOR * 2], b'A' | 0x80);
+
+        // Sectors after the hole get freed too
+        disk.delete_file("RECORDS").unwrap();
+        assert!(disk.is_free(last_track, last_sector));
+    }
+
+    #[test]
+    fn disk_full() {
+        let mut disk = Dos33Disk::format(254);
+        disk.write_file("BIG1", &binary(0x2000, 60000, 1), false)
+            .unwrap();
+        disk.write_file("BIG2", &binary(0x2000, 60000, 2), false)
+            .unwrap();
+
+        assert_eq!(
+            disk.write_file("BIG3", &binary(0x2000, 60000, 3), false)
+                .err(),
+            Some("Disk is full.")
+        );
+        assert_eq!(disk.catalog().unwrap().len(), 2);
+
+        // Replacing a file can reuse its sectors, and a replacement that doesn't fit keeps it
+        disk.write_file("BIG1", &binary(0x2000, 60000, 4), false)
+            .unwrap();
+        assert!(disk
+            .write_file("BIG2", &binary(0x2000, 65535, 5), false)
+            .is_err());
+        assert_eq!(
+            disk.read_file("BIG1").unwrap().data,
+            binary(0x2000, 60000, 4).data
+        );
+        assert_eq!(
+            disk.read_file("BIG2").unwrap().data,
+            binary(0x2000, 60000, 2).data
+        );
+    }
+}
@@ -3,22 +3,67 @@ Converts a DSK image into WOZ2 format
 Heavily inspired by: https://github.com/mr-stivo/dsk2woz2/blob/master/dsk2woz2.c
 Essentially a rewrite in Rust
 
+13-sector (DOS 3.2) images are converted using 5 and 3 encoding, based on the description in
+Beneath Apple DOS and the nibble code of CiderPress.
+
 WOZ Reference: https://applesaucefdc.com/woz/reference2/
 */
 
-use std::path::Path;
-use std::{fs::File, io::Read};
-
-const DSK_IMG_SIZE: usize = 143360;
-
 const NUM_TRACKS: u32 = 35;
 const BLOCK_SIZE: u32 = 512;
 const BLOCKS_PER_TRACK: u32 = 13;
-const BITS_PER_TRACK: u32 = 50304;
 
-const NUM_SECTORS: u32 = 16;
 const BYTES_PER_SECTOR: u32 = 256;
 const GCR_BYTES_PER_SECTOR: u32 = 343;
+const GCR_BYTES_PER_SECTOR_5_3: usize = 411;
+const CHUNK_SIZE_5_3: usize = 51;
+
+pub const DEFAULT_VOLUME: u8 = 254;
+
+// The disk bytes that each 6-bit (or 5-bit) value gets written as
+pub const TRANSLATE_6_2: [u8; 64] = [
+    0x96, 0x97, 0x9A, 0x9B, 0x9D, 0x9E, 0x9F, 0xA6, 0xA7, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF, 0xB2, 0xB3,
+    0xB4, 0xB5, 0xB6, 0xB7, 0xB9, 0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF, 0xCB, 0xCD, 0xCE, 0xCF, 0xD3,
+    0xD6, 0xD7, 0xD9, 0xDA, 0xDB, 0xDC, 0xDD, 0xDE, 0xDF, 0xE5, 0xE6, 0xE7, 0xE9, 0xEA, 0xEB, 0xEC,
+    0xED, 0xEE, 0xEF, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF9, 0xFA, 0xFB, 0xFC, 0xFD, 0xFE, 0xFF,
+];
+
+pub const TRANSLATE_5_3: [u8; 32] = [
+    0xAB, 0xAD, 0xAE, 0xAF, 0xB5, 0xB6, 0xB7, 0xBA, 0xBB, 0xBD, 0xBE, 0xBF, 0xD6, 0xD7, 0xDA, 0xDB,
+    0xDD, 0xDE, 0xDF, 0xEA, 0xEB, 0xED, 0xEE, 0xEF, 0xF5, 0xF6, 0xF7, 0xFA, 0xFB, 0xFD, 0xFE, 0xFF,
+];
+
+// The sector layout of the image being converted
+#[derive(Clone, Copy, PartialEq)]
+pub enum SectorFormat {
+    Dos33,
+    ProDos,
+    Dos32,
+}
+
+impl SectorFormat {
+    pub fn num_sectors(&self) -> u32 {
+        match self {
+            SectorFormat::Dos32 => 13,
+            _ => 16,
+        }
+    }
+
+    pub fn image_size(&self) -> usize {
+        (NUM_TRACKS * self.num_sectors() * BYTES_PER_SECTOR) as usize
+    }
+
+    // Which sector of the image gets stored in the given physical sector of a track
+    pub fn logical_sector(&self, physical_sector: usize) -> usize {
+        // 13-sector images have no interleave, sectors are stored in physical order
+        match self {
+            SectorFormat::Dos32 => physical_sector,
+            _ if physical_sector == 15 => 15,
+            SectorFormat::ProDos => (physical_sector * 8) % 15,
+            SectorFormat::Dos33 => (physical_sector * 7) % 15,
+        }
+    }
+}
 
 mod section_id {
     pub const WOZ2: u32 = 0x325A4F57;
@@ -44,7 +89,7 @@ fn fill_header(woz: &mut [u8]) {
     put_u32(0x00000000, woz, 8);
 }
 
-fn fill_info(woz: &mut [u8]) {
+fn fill_info(woz: &mut [u8], format: SectorFormat) {
     put_u32(section_id::INFO, woz, 12);
     put_u32(60, woz, 16);
     woz[20] = 2;
@@ -52,9 +97,12 @@ fn fill_info(woz: &mut [u8]) {
     woz[22] = 0;
     woz[23] = 0;
     woz[24] = 1;
-    woz[24..57].fill(0x20);
+    woz[25..57].fill(0x20);
     woz[57] = 1;
-    woz[58] = 0;
+    woz[58] = match format {
+        SectorFormat::Dos32 => 2,
+        _ => 1,
+    };
     woz[59] = 32;
     put_u16(0, woz, 60);
     put_u16(0, woz, 62);
@@ -73,7 +121,7 @@ fn fill_tmap(woz: &mut [u8]) {
     }
 }
 
-fn fill_trks(woz: &mut [u8], file_buf: &[u8], is_prodos: bool) {
+fn fill_trks(woz: &mut [u8], file_buf: &[u8], format: SectorFormat, volume: u8) {
     put_u32(section_id::TRKS, woz, 248);
     put_u32(
         1280 + (BLOCK_SIZE * BLOCKS_PER_TRACK * NUM_TRACKS),
@@ -81,22 +129,23 @@ fn fill_trks(woz: &mut [u8], file_buf: &[u8], is_prodos: bool) {
         252,
     );
 
-    for i in 0..NUM_TRACKS {
-        let idx = (BYTES_PER_SECTOR + (i * 8)) as usize;
-        put_u16(3 + (i as u16 * BLOCKS_PER_TRACK as u16), woz, idx);
-        put_u16(BLOCKS_PER_TRACK as u16, woz, idx + 2);
-        put_u16(BITS_PER_TRACK as u16, woz, idx + 4);
-    }
-
     let mut woz_idx = 0x600; // Start address of first track
     for i in 0..NUM_TRACKS {
-        let dsk_idx = (BYTES_PER_SECTOR * NUM_SECTORS * i) as usize;
-        convert_track(
+        let dsk_idx = (BYTES_PER_SECTOR * format.num_sectors() * i) as usize;
+        let bit_count = convert_track(
             &mut woz[woz_idx..],
             &file_buf[dsk_idx..],
             i as u8,
-            is_prodos,
+            format,
+            volume,
         );
+
+        // 13-sector tracks are shorter, so fill in the bit count after converting
+        let idx = (BYTES_PER_SECTOR + (i * 8)) as usize;
+        put_u16(3 + (i as u16 * BLOCKS_PER_TRACK as u16), woz, idx);
+        put_u16(BLOCKS_PER_TRACK as u16, woz, idx + 2);
+        put_u32(bit_count, woz, idx + 4);
+
         woz_idx += (BLOCK_SIZE * BLOCKS_PER_TRACK) as usize;
     }
 }
@@ -128,14 +177,6 @@ fn write_sync(woz: &mut [u8], bit_pntr: &mut usize) {
 }
 
 fn convert_6_2(dsk: &[u8]) -> [u8; GCR_BYTES_PER_SECTOR as usize] {
-    let map = [
-        0x96, 0x97, 0x9A, 0x9B, 0x9D, 0x9E, 0x9F, 0xA6, 0xA7, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF, 0xB2,
-        0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB9, 0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF, 0xCB, 0xCD, 0xCE,
-        0xCF, 0xD3, 0xD6, 0xD7, 0xD9, 0xDA, 0xDB, 0xDC, 0xDD, 0xDE, 0xDF, 0xE5, 0xE6, 0xE7, 0xE9,
-        0xEA, 0xEB, 0xEC, 0xED, 0xEE, 0xEF, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF9, 0xFA, 0xFB,
-        0xFC, 0xFD, 0xFE, 0xFF,
-    ];
-
     let mut gcr_bytes = [0; GCR_BYTES_PER_SECTOR as usize];
 
     let bit_reverse = [0, 2, 1, 3];
@@ -161,13 +202,50 @@ fn convert_6_2(dsk: &[u8]) -> [u8; GCR_BYTES_PER_SECTOR as usize] {
     }
 
     for i in 0..GCR_BYTES_PER_SECTOR as usize {
-        gcr_bytes[i] = map[gcr_bytes[i] as usize];
+        gcr_bytes[i] = TRANSLATE_6_2[gcr_bytes[i] as usize];
     }
 
     gcr_bytes
 }
 
-fn convert_track(woz: &mut [u8], dsk: &[u8], track: u8, is_prodos: bool) {
+fn convert_5_3(dsk: &[u8]) -> [u8; GCR_BYTES_PER_SECTOR_5_3] {
+    let mut top = [0; BYTES_PER_SECTOR as usize];
+    let mut threes = [0; CHUNK_SIZE_5_3 * 3 + 1];
+
+    /* Split every group of 5 bytes into their upper 5 bits, and spread their lower 3 bits across
+    three "threes" bytes. Groups are stored in reverse order. */
+    for (i, group) in dsk[..CHUNK_SIZE_5_3 * 5].chunks(5).enumerate() {
+        let chunk = CHUNK_SIZE_5_3 - 1 - i;
+
+        for (j, byte) in group.iter().enumerate() {
+            top[chunk + CHUNK_SIZE_5_3 * j] = byte >> 3;
+        }
+
+        threes[chunk] = ((group[0] & 7) << 2) | ((group[3] & 4) >> 1) | ((group[4] & 4) >> 2);
+        threes[chunk + CHUNK_SIZE_5_3] =
+            ((group[1] & 7) << 2) | (group[3] & 2) | ((group[4] & 2) >> 1);
+        threes[chunk + CHUNK_SIZE_5_3 * 2] =
+            ((group[2] & 7) << 2) | ((group[3] & 1) << 1) | (group[4] & 1);
+    }
+
+    // The last byte doesn't fit into a group so gets handled on its own
+    top[255] = dsk[255] >> 3;
+    threes[CHUNK_SIZE_5_3 * 3] = dsk[255] & 7;
+
+    /* The threes get written first (in reverse), followed by the top bytes, with each value
+    XORed with the previous one. Then finally the checksum. */
+    let mut gcr_bytes = [0; GCR_BYTES_PER_SECTOR_5_3];
+    let mut prev = 0;
+    for (i, val) in threes.iter().rev().chain(top.iter()).enumerate() {
+        gcr_bytes[i] = TRANSLATE_5_3[(val ^ prev) as usize];
+        prev = *val;
+    }
+    gcr_bytes[GCR_BYTES_PER_SECTOR_5_3 - 1] = TRANSLATE_5_3[prev as usize];
+
+    gcr_bytes
+}
+
+fn convert_track(woz: &mut [u8], dsk: &[u8], track: u8, format: SectorFormat, volume: u8) -> u32 {
     let mut bit_pntr = 0;
 
     // Gap 1
@@ -175,17 +253,24 @@ fn convert_track(woz: &mut [u8], dsk: &[u8], track: u8, is_prodos: bool) {
         write_sync(woz, &mut bit_pntr);
     }
 
-    for i in 0..NUM_SECTORS as u8 {
-        // Address Prologue
+    for i in 0..format.num_sectors() as u8 {
+        // Address Prologue (13-sector disks use a different final byte)
         write_byte(woz, &mut bit_pntr, 0xD5);
         write_byte(woz, &mut bit_pntr, 0xAA);
-        write_byte(woz, &mut bit_pntr, 0x96);
+        write_byte(
+            woz,
+            &mut bit_pntr,
+            match format {
+                SectorFormat::Dos32 => 0xB5,
+                _ => 0x96,
+            },
+        );
 
         // Volume, track, sector, and checksum
-        write_4_4(woz, &mut bit_pntr, 254);
+        write_4_4(woz, &mut bit_pntr, volume);
         write_4_4(woz, &mut bit_pntr, track);
         write_4_4(woz, &mut bit_pntr, i);
-        write_4_4(woz, &mut bit_pntr, 254 ^ track ^ i);
+        write_4_4(woz, &mut bit_pntr, volume ^ track ^ i);
 
         // Address Epilogue
         write_byte(woz, &mut bit_pntr, 0xDE);
@@ -202,19 +287,19 @@ fn convert_track(woz: &mut [u8], dsk: &[u8], track: u8, is_prodos: bool) {
         write_byte(woz, &mut bit_pntr, 0xAA);
         write_byte(woz, &mut bit_pntr, 0xAD);
 
-        let scalar = match is_prodos {
-            true => 8,
-            false => 7,
-        };
-        let logical_sector = match i == 15 {
-            true => 15,
-            false => (i as usize * scalar) % 15,
-        };
-
-        // Convert 256 data bytes into 343 6 and 2 encoded disk bytes
-        let gcr_bytes = convert_6_2(&dsk[logical_sector * BYTES_PER_SECTOR as usize..]);
-        for b in gcr_bytes.into_iter() {
-            write_byte(woz, &mut bit_pntr, b);
+        let logical_sector = format.logical_sector(i as usize);
+        let sector_data = &dsk[logical_sector * BYTES_PER_SECTOR as usize..];
+
+        if format == SectorFormat::Dos32 {
+            // Convert 256 data bytes into 411 5 and 3 encoded disk bytes
+            for b in convert_5_3(sector_data).into_iter() {
+                write_byte(woz, &mut bit_pntr, b);
+            }
+        } else {
+            // Convert 256 data bytes into 343 6 and 2 encoded disk bytes
+            for b in convert_6_2(sector_data).into_iter() {
+                write_byte(woz, &mut bit_pntr, b);
+            }
         }
 
         // Data Epilogue
@@ -227,17 +312,13 @@ fn convert_track(woz: &mut [u8], dsk: &[u8], track: u8, is_prodos: bool) {
             write_sync(woz, &mut bit_pntr);
         }
     }
-}
 
-pub fn convert(file_path: &Path, woz: &mut [u8], is_prodos: bool) {
-    let mut file_buf = [0; DSK_IMG_SIZE];
-    let mut image = File::open(file_path).expect("Failed to open DSK image!");
-    image
-        .read_exact(&mut file_buf)
-        .expect("Failed to read DSK image data!");
+    bit_pntr as u32
+}
 
+pub fn convert(dsk: &[u8], woz: &mut [u8], format: SectorFormat, volume: u8) {
     fill_header(woz);
-    fill_info(woz);
+    fill_info(woz, format);
     fill_tmap(woz);
-    fill_trks(woz, &file_buf, is_prodos);
+    fill_trks(woz, dsk, format, volume);
 }
@@ -1,22 +1,106 @@
-use sdl2::pixels::PixelFormatEnum;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
 use sdl2::render::TextureCreator;
-use sdl2::video::WindowContext;
+use sdl2::video::{FullscreenType, WindowContext};
 use sdl2::{render::Canvas, render::Texture, video::Window};
 
-use std::{fs::File, io::Read};
+use std::io::BufWriter;
+use std::path::Path;
+use std::{fs, fs::File, io::Read};
 
 pub const DISP_WIDTH: u32 = 280;
 pub const DISP_HEIGHT: u32 = 192;
 pub const DISP_SCALE: u32 = 3;
+pub const MAX_SCALE: u32 = 6;
+
+// A 4:3 monitor shows the 192 lines of the display as wide as 256 square pixels
+const ASPECT_WIDTH: u32 = DISP_HEIGHT * 4 / 3;
+
+/* Every line is drawn as two rows of the pixel buffer, so the second row can be darkened to look
+like the gaps between the scanlines of a monitor. */
+const ROWS_PER_LINE: usize = 2;
+const SCANLINE_BRIGHTNESS: u32 = 50; // Percent
+
+/* The video signal is built from 560 dots per line (at 14MHz), twice the number of pixels,
+since hires bytes with the high bit set get shifted over by half a pixel. */
+const DOTS_PER_LINE: usize = DISP_WIDTH as usize * 2;
+const DOTS_PER_BLOCK: usize = BLOCK_WIDTH as usize * 2;
 
 const PIXEL_SIZE: u32 = 3;
-const BLOCK_ROWS: usize = 24;
 const BLOCK_COLS: usize = 40;
 const BLOCK_WIDTH: u32 = 7;
 const BLOCK_HEIGHT: u32 = 8;
+const MIXED_TEXT_ROW: usize = 20;
 const CHAR_ROM_SIZE: usize = 0x800;
 const FLASH_RATE: u32 = 4;
-const BYTES_PER_BLOCK_ROW: usize = BLOCK_COLS * (BLOCK_WIDTH * PIXEL_SIZE) as usize;
+const BYTES_PER_LINE: usize = DOTS_PER_LINE * PIXEL_SIZE as usize;
+const DEFAULT_CHAR_ROM: &str = "roms/firmware/char_set.rom";
+
+/* A lowercase adapter shows bytes $E0-$FF as lowercase letters (and a few symbols), instead of
+repeating the symbols and digits of $A0-$BF. Lowercase character ROMs keep these glyphs where
+ASCII puts lowercase ($60-$7F), while the ROM of a stock Apple 2+ has no lowercase at all. */
+const LOWERCASE_START: u8 = 0xE0;
+const NUM_LOWERCASE: usize = 32;
+const LOWERCASE_ROM_START: usize = 0x60 * BLOCK_HEIGHT as usize;
+const SYMBOLS_ROM_START: usize = 0x20 * BLOCK_HEIGHT as usize;
+const LOWERCASE_ROM_SIZE: usize = NUM_LOWERCASE * BLOCK_HEIGHT as usize;
+
+// Lowercase glyphs for character ROMs without any, in the same format (the leftmost dot is bit 6)
+const LOWERCASE_GLYPHS: [[u8; BLOCK_HEIGHT as usize]; NUM_LOWERCASE] = [
+    [0x10, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x1C, 0x02, 0x1E, 0x22, 0x1E, 0x00], // a
+    [0x20, 0x20, 0x3C, 0x22, 0x22, 0x22, 0x3C, 0x00], // b
+    [0x00, 0x00, 0x1E, 0x20, 0x20, 0x20, 0x1E, 0x00], // c
+    [0x02, 0x02, 0x1E, 0x22, 0x22, 0x22, 0x1E, 0x00], // d
+    [0x00, 0x00, 0x1C, 0x22, 0x3E, 0x20, 0x1E, 0x00], // e
+    [0x0C, 0x12, 0x10, 0x3C, 0x10, 0x10, 0x10, 0x00], // f
+    [0x00, 0x00, 0x1C, 0x22, 0x22, 0x1E, 0x02, 0x1C], // g
+    [0x20, 0x20, 0x3C, 0x22, 0x22, 0x22, 0x22, 0x00], // h
+    [0x08, 0x00, 0x18, 0x08, 0x08, 0x08, 0x1C, 0x00], // i
+    [0x04, 0x00, 0x0C, 0x04, 0x04, 0x04, 0x24, 0x18], // j
+    [0x20, 0x20, 0x22, 0x24, 0x38, 0x24, 0x22, 0x00], // k
+    [0x18, 0x08, 0x08, 0x08, 0x08, 0x08, 0x1C, 0x00], // l
+    [0x00, 0x00, 0x34, 0x2A, 0x2A, 0x2A, 0x22, 0x00], // m
+    [0x00, 0x00, 0x3C, 0x22, 0x22, 0x22, 0x22, 0x00], // n
+    [0x00, 0x00, 0x1C, 0x22, 0x22, 0x22, 0x1C, 0x00], // o
+    [0x00, 0x00, 0x3C, 0x22, 0x22, 0x3C, 0x20, 0x20], // p
+    [0x00, 0x00, 0x1E, 0x22, 0x22, 0x1E, 0x02, 0x02], // q
+    [0x00, 0x00, 0x2E, 0x30, 0x20, 0x20, 0x20, 0x00], // r
+    [0x00, 0x00, 0x1E, 0x20, 0x1C, 0x02, 0x3C, 0x00], // s
+    [0x10, 0x10, 0x3C, 0x10, 0x10, 0x12, 0x0C, 0x00], // t
+    [0x00, 0x00, 0x22, 0x22, 0x22, 0x26, 0x1A, 0x00], // u
+    [0x00, 0x00, 0x22, 0x22, 0x22, 0x14, 0x08, 0x00], // v
+    [0x00, 0x00, 0x22, 0x22, 0x2A, 0x2A, 0x14, 0x00], // w
+    [0x00, 0x00, 0x22, 0x14, 0x08, 0x14, 0x22, 0x00], // x
+    [0x00, 0x00, 0x22, 0x22, 0x22, 0x1E, 0x02, 0x1C], // y
+    [0x00, 0x00, 0x3E, 0x04, 0x08, 0x10, 0x3E, 0x00], // z
+    [0x06, 0x08, 0x08, 0x10, 0x08, 0x08, 0x06, 0x00], // {
+    [0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00], // |
+    [0x30, 0x08, 0x08, 0x04, 0x08, 0x08, 0x30, 0x00], // }
+    [0x12, 0x2A, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00], // ~
+    [0x14, 0x2A, 0x14, 0x2A, 0x14, 0x2A, 0x14, 0x00], // DEL
+];
+
+/* A color cycle of the NTSC signal lasts exactly 4 dots, so every 4 dots in a row make up a color.
+The color a group of dots produces depends on where they fall in the color cycle. */
+const DOTS_PER_COLOR: usize = 4;
+const NUM_COLORS: usize = 1 << DOTS_PER_COLOR;
+
+/* The video scanner fetches a byte of video memory every CPU cycle. A scanline lasts 65 cycles,
+the first 25 of which are spent in the horizontal blank, and a frame is made of 262 scanlines, of
+which the first 192 are shown and the rest are spent in the vertical blank. */
+const CYCLES_PER_LINE: usize = 65;
+const HBLANK_CYCLES: usize = CYCLES_PER_LINE - BLOCK_COLS;
+const LINES_PER_FRAME: usize = 262;
+pub const CYCLES_PER_FRAME: usize = CYCLES_PER_LINE * LINES_PER_FRAME;
+
+/* The vertical counter of the scanner counts from $100 to $1FF for the first 256 lines, then from
+$FA to $FF for the last 6. The horizontal counter is $00 for the first cycle of the line, then counts
+from $40 to $7F, with the 40 bytes shown fetched at $58-$7F. */
+const V_COUNT_START: usize = 0x100;
+const V_COUNT_WRAP: usize = 0x200;
+const V_COUNT_RESTART: usize = 0xFA;
+const H_COUNT_START: usize = 0x3F;
 
 mod soft_switch {
     pub const GFX_MODE: usize = 0xC050;
@@ -30,49 +114,264 @@ mod soft_switch {
 }
 
 mod color {
-    // All
     pub const BLACK: u32 = 0x000000;
     pub const WHITE: u32 = 0xFFFFFF;
 
-    // LORES
-    pub const MAGENTA: u32 = 0x901740;
-    pub const DARK_BLUE: u32 = 0x402CA5;
-    pub const PURPLE: u32 = 0xD043E5;
-    pub const DARK_GREEN: u32 = 0x006940;
-    pub const GREY1: u32 = 0x808080;
-    pub const BLUE: u32 = 0x2F95E5;
-    pub const LIGHT_BLUE: u32 = 0xBFABFF;
-    pub const BROWN: u32 = 0x405400;
-    pub const ORANGE: u32 = 0xD06A1A;
-    pub const GREY2: u32 = 0x808080;
-    pub const PINK: u32 = 0xFF96BF;
-    pub const LIGHT_GREEN: u32 = 0x2FBC1A;
-    pub const YELLOW: u32 = 0xBFD35A;
-    pub const AQUA: u32 = 0x6FE8BF;
-
-    // HIRES
-    pub const HIRES_BLUE: u32 = 0x4BB8F1;
-    pub const HIRES_ORANGE: u32 = 0xE6792E;
-    pub const HIRES_VIOLET: u32 = 0xD660EF;
-    pub const HIRES_GREEN: u32 = 0x68E043;
+    /* Palettes hold the 16 colors the NTSC signal can produce, in the order of the lores color
+    numbers. The bits of a color number are the dots it puts out over one color cycle. The hires
+    colors are the same as lores colors 3 (violet), 6 (blue), 9 (orange) and 12 (green). */
+
+    /* Colors as measured from a real Apple 2 on an NTSC monitor. Both greys come out of the
+    exact same signal (every other dot on), so they really are identical. */
+    pub const NTSC_PALETTE: [u32; 16] = [
+        BLACK,    // Black
+        0x901740, // Magenta
+        0x402CA5, // Dark blue
+        0xD043E5, // Purple
+        0x006940, // Dark green
+        0x808080, // Grey 1
+        0x2F95E5, // Medium blue
+        0xBFABFF, // Light blue
+        0x405400, // Brown
+        0xD06A1A, // Orange
+        0x808080, // Grey 2
+        0xFF96BF, // Pink
+        0x2FBC1A, // Light green
+        0xBFD35A, // Yellow
+        0x6FE8BF, // Aqua
+        WHITE,    // White
+    ];
+
+    // The more saturated colors used by AppleWin
+    pub const APPLEWIN_PALETTE: [u32; 16] = [
+        BLACK,    // Black
+        0x9D0966, // Magenta
+        0x2A2AE5, // Dark blue
+        0xC734FF, // Purple
+        0x008000, // Dark green
+        0x808080, // Grey 1
+        0x0DA1FF, // Medium blue
+        0xAAAAFF, // Light blue
+        0x555500, // Brown
+        0xF25E00, // Orange
+        0xC0C0C0, // Grey 2
+        0xFF89E5, // Pink
+        0x38CB00, // Light green
+        0xD5D51A, // Yellow
+        0x62F699, // Aqua
+        WHITE,    // White
+    ];
+
+    // The colors the Apple 2GS uses for its lores and hires modes, which has a dark and light grey
+    pub const IIGS_PALETTE: [u32; 16] = [
+        BLACK,    // Black
+        0xDD0033, // Magenta
+        0x000099, // Dark blue
+        0xDD22DD, // Purple
+        0x007722, // Dark green
+        0x555555, // Grey 1
+        0x2222FF, // Medium blue
+        0x66AAFF, // Light blue
+        0x885500, // Brown
+        0xFF6600, // Orange
+        0xAAAAAA, // Grey 2
+        0xFF9988, // Pink
+        0x11DD00, // Light green
+        0xFFFF00, // Yellow
+        0x44FF99, // Aqua
+        WHITE,    // White
+    ];
+
+    // Phosphor colors of monochrome monitors
+    pub const GREEN_PHOSPHOR: u32 = 0x33FF66;
+    pub const AMBER_PHOSPHOR: u32 = 0xFFB000;
+}
+
+// How big the display is drawn in the window
+#[derive(Clone, Copy)]
+pub enum Scale {
+    Integer(u32),
+    Fit,
+}
+
+impl Scale {
+    pub fn name(&self) -> String {
+        match self {
+            Scale::Integer(scale) => format!("{scale}x"),
+            Scale::Fit => "fit to window".to_string(),
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            Scale::Integer(scale) if *scale < MAX_SCALE => Scale::Integer(scale + 1),
+            Scale::Integer(_) => Scale::Fit,
+            Scale::Fit => Scale::Integer(1),
+        }
+    }
+}
+
+// The kind of monitor hooked up to the Apple 2
+#[derive(Clone, Copy)]
+pub enum Monitor {
+    Color,
+    White,
+    Green,
+    Amber,
+}
+
+impl Monitor {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Monitor::Color => "color",
+            Monitor::White => "white monochrome",
+            Monitor::Green => "green monochrome",
+            Monitor::Amber => "amber monochrome",
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            Monitor::Color => Monitor::White,
+            Monitor::White => Monitor::Green,
+            Monitor::Green => Monitor::Amber,
+            Monitor::Amber => Monitor::Color,
+        }
+    }
+
+    // Monochrome monitors just light up a dot wherever the signal is high
+    fn phosphor(&self) -> Option<u32> {
+        match self {
+            Monitor::Color => None,
+            Monitor::White => Some(color::WHITE),
+            Monitor::Green => Some(color::GREEN_PHOSPHOR),
+            Monitor::Amber => Some(color::AMBER_PHOSPHOR),
+        }
+    }
+}
+
+/* Where the video scanner is and which soft switches are set, which is all it takes to know what
+byte of memory the scanner is fetching at any cycle. */
+#[derive(Clone, Copy, Default)]
+pub struct VideoScanner {
+    scan_cycle: usize,
+    txt_mode: bool,
+    hires_mode: bool,
+    mixed_mode: bool,
+    use_pg2: bool,
+}
+
+impl VideoScanner {
+    /* The scanner keeps fetching bytes during the blanking periods too, using the same address
+    logic as the rest of the screen (see Understanding the Apple II by Jim Sather, chapter 5). */
+    pub fn fetch_address(&self, cycles_ahead: usize) -> usize {
+        let scan_cycle = (self.scan_cycle + cycles_ahead) % CYCLES_PER_FRAME;
+        let line = scan_cycle / CYCLES_PER_LINE;
+        let h_cycle = scan_cycle % CYCLES_PER_LINE;
+
+        let v_count = match V_COUNT_START + line < V_COUNT_WRAP {
+            true => V_COUNT_START + line,
+            false => V_COUNT_RESTART + line - (V_COUNT_WRAP - V_COUNT_START),
+        };
+        let h_count = match h_cycle {
+            0 => 0,
+            _ => H_COUNT_START + h_cycle,
+        };
+
+        let row = v_count & 0x07;
+        let block_row = (v_count >> 3) & 0x1F;
+
+        // The low 7 bits come from adding the horizontal count to the third of the screen
+        let third = block_row >> 3;
+        let low_bits = ((h_count & 0x3F) + 0x28 * third + 0x68) & 0x7F;
+        let offset = 0x80 * (block_row & 0x07) + low_bits;
+
+        // Mixed mode shows text whenever V4 and V2 of the vertical count are both set
+        let text_line = self.txt_mode || (self.mixed_mode && block_row & 0x14 == 0x14);
+
+        match (text_line || !self.hires_mode, self.use_pg2) {
+            (true, false) => 0x400 + offset,
+            (true, true) => 0x800 + offset,
+            (false, false) => 0x2000 + 0x400 * row + offset,
+            (false, true) => 0x4000 + 0x400 * row + offset,
+        }
+    }
+}
+
+// A set of colors for the color monitor to decode the signal into
+struct Palette {
+    name: String,
+    colors: [u32; NUM_COLORS],
+}
+
+impl Palette {
+    fn new(name: &str, colors: [u32; NUM_COLORS]) -> Self {
+        Palette {
+            name: name.to_string(),
+            colors,
+        }
+    }
+
+    /* A palette file lists the 16 colors as hex RGB values (such as FF6600 or #FF6600), one per
+    line, in the order of the lores color numbers. Anything after the color on a line is ignored,
+    so the colors can be named, and blank lines are skipped. */
+    fn load(file_path: &Path) -> Result<Self, &'static str> {
+        let palette_file =
+            fs::read_to_string(file_path).map_err(|_| "Failed to read palette file.")?;
+
+        let mut colors = Vec::new();
+        for color in palette_file
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+        {
+            let color = color.trim_start_matches(['#', '$']);
+            match color.len() == 6 {
+                true => colors.push(
+                    u32::from_str_radix(color, 16).map_err(|_| "Invalid color in palette file.")?,
+                ),
+                false => return Err("Invalid color in palette file."),
+            }
+        }
+
+        let colors = colors
+            .try_into()
+            .map_err(|_| "Palette file must have exactly 16 colors.")?;
+        let name = file_path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(Palette { name, colors })
+    }
 }
 
 pub struct GraphicsHandler<'a> {
     canvas: &'a mut Canvas<Window>,
-    pixel_buf: [u8; (DISP_WIDTH * DISP_HEIGHT * PIXEL_SIZE) as usize],
+    pixel_buf: Vec<u8>,
     pixel_surface: Texture<'a>,
+    lines: Vec<[u8; DOTS_PER_LINE]>,
+    color_burst: Vec<bool>,
+    scan_cycle: usize,
     char_data: [u8; CHAR_ROM_SIZE],
+    lowercase_glyphs: [[u8; BLOCK_HEIGHT as usize]; NUM_LOWERCASE],
+    lowercase_adapter: bool,
     frame_count: u32,
     flash: bool,
     txt_mode: bool,
     hires_mode: bool,
     mixed_mode: bool,
     use_pg2: bool,
+    monitor: Monitor,
+    scale: Scale,
+    aspect_correct: bool,
+    scanlines: bool,
+    blur: bool,
+    fullscreen: bool,
+    palettes: Vec<Palette>,
+    palette: usize,
 }
 
-fn load_char_set() -> [u8; CHAR_ROM_SIZE] {
-    let mut char_rom =
-        File::open("roms/firmware/char_set.rom").expect("Failed to open charset ROM!");
+fn load_char_set(file_path: &Path) -> [u8; CHAR_ROM_SIZE] {
+    let mut char_rom = File::open(file_path).expect("Failed to open charset ROM!");
 
     let mut char_array = [0; CHAR_ROM_SIZE];
     char_rom
@@ -81,86 +380,83 @@ fn load_char_set() -> [u8; CHAR_ROM_SIZE] {
     char_array
 }
 
-fn block_to_pbuf_idx(block_idx: usize) -> usize {
-    let row = (block_idx / BLOCK_COLS) as u32;
-    let col = (block_idx % BLOCK_COLS) as u32;
+// Uses the lowercase glyphs of the character ROM, unless they are just a copy of the symbols
+fn lowercase_glyphs(
+    char_data: &[u8; CHAR_ROM_SIZE],
+) -> [[u8; BLOCK_HEIGHT as usize]; NUM_LOWERCASE] {
+    let rom_glyphs = &char_data[LOWERCASE_ROM_START..LOWERCASE_ROM_START + LOWERCASE_ROM_SIZE];
+    let symbol_glyphs = &char_data[SYMBOLS_ROM_START..SYMBOLS_ROM_START + LOWERCASE_ROM_SIZE];
+
+    let is_copy =
+        rom_glyphs == symbol_glyphs || rom_glyphs.iter().zip(symbol_glyphs).all(|(l, s)| *l == !*s);
+    if is_copy {
+        return LOWERCASE_GLYPHS;
+    }
 
-    (row * (BLOCK_HEIGHT * BYTES_PER_BLOCK_ROW as u32) + col * (BLOCK_WIDTH * PIXEL_SIZE)) as usize
+    let mut glyphs = [[0; BLOCK_HEIGHT as usize]; NUM_LOWERCASE];
+    for (glyph, rom_glyph) in glyphs
+        .iter_mut()
+        .zip(rom_glyphs.chunks(BLOCK_HEIGHT as usize))
+    {
+        glyph.copy_from_slice(rom_glyph);
+    }
+    glyphs
 }
 
-fn to_pixel_map(buffer: &[u8], buf_idx: usize, block_col: usize) -> [u32; BLOCK_WIDTH as usize] {
-    // The pixels for this block in order
-    let mut pixel_map = [color::BLACK; BLOCK_WIDTH as usize];
-
-    let val = buffer[buf_idx];
-    let alt_colors = (val >> 7) != 0; // If MSB is high, use alternate color palette
-
-    // We need to check bordering dots, even if in adjacent bytes
-    let left_block_dot = match block_col != 0 {
-        true => (buffer[buf_idx - 1] >> 6) & 1,
-        false => 0,
-    };
-    let right_block_dot = match block_col != (BLOCK_COLS - 1) {
-        true => buffer[buf_idx + 1] & 1,
-        false => 0,
-    };
-
-    /* Scan each bit (except the MSB), mapping it to a color depending on its value and its
-    neighboring bits . */
-    let mut tmp = val;
-    for (i, pixel) in pixel_map.iter_mut().enumerate() {
-        let dot = tmp & 1;
-        let left_dot = match i == 0 {
-            true => left_block_dot,
-            false => val >> (i - 1) & 1,
-        };
-        let right_dot = match i == 6 {
-            true => right_block_dot,
-            false => val >> (i + 1) & 1,
-        };
+/* The Apple 2 video memory mapping is crazy (though it makes sense why it is the way that it is).
+Every group of 8 block rows is interleaved, and the three thirds of the screen sit side by side. */
+fn block_row_offset(block_row: usize) -> usize {
+    0x80 * (block_row % 8) + 0x28 * (block_row / 8)
+}
 
-        // "Evenness" depends on block column and position within block
-        let is_even =
-            ((block_col % 2 == 0) && (i % 2 == 0)) || ((block_col % 2 == 1) && (i % 2 == 1));
-
-        let color = if dot != 0 {
-            // Any high bit bordering another high bit becomes a white dot
-            if left_dot == 1 || right_dot == 1 {
-                color::WHITE
-            } else if alt_colors && is_even {
-                color::HIRES_BLUE
-            } else if alt_colors && !is_even {
-                color::HIRES_ORANGE
-            } else if !alt_colors && is_even {
-                color::HIRES_VIOLET
-            } else {
-                color::HIRES_GREEN
-            }
+// Mixes each channel of three colors, weighing the middle color twice as much as its neighbors
+fn blend(left: u32, middle: u32, right: u32) -> u32 {
+    (0..3).fold(0, |blended, i| {
+        let shift = 8 * i;
+        let channel = |color: u32| (color >> shift) & 0xFF;
+        let mixed = (channel(left) + 2 * channel(middle) + channel(right)) / 4;
+        blended | (mixed << shift)
+    })
+}
 
-        /* If the bit is low, but borders a high bit to its right, incorporate "fringing"
-        This is not perfect, fringing is difficult to get right with its half pixel shifts
-        and whatnot. */
-        } else if right_dot == 1 {
-            if alt_colors && !is_even {
-                color::HIRES_BLUE
-            } else if alt_colors && is_even {
-                color::HIRES_ORANGE
-            } else if !alt_colors && !is_even {
-                color::HIRES_VIOLET
-            } else {
-                color::HIRES_GREEN
-            }
+fn darken(color: u32) -> u32 {
+    (0..3).fold(0, |darkened, i| {
+        let shift = 8 * i;
+        let channel = ((color >> shift) & 0xFF) * SCANLINE_BRIGHTNESS / 100;
+        darkened | (channel << shift)
+    })
+}
 
-        // Otherwise just draw a black pixel
-        } else {
-            color::BLACK
-        };
+// Saves RGB pixels as a PNG image
+pub fn write_png(
+    file_path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+) -> Result<(), &'static str> {
+    let file = File::create(file_path).map_err(|_| "Failed to create PNG file.")?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(pixels))
+        .map_err(|_| "Failed to write PNG file.")
+}
+
+// Works out the color of each dot from the 4 dots (one color cycle) around it
+fn decode_color(dots: &[u8; DOTS_PER_LINE], x: usize, palette: &[u32; NUM_COLORS]) -> u32 {
+    let start = x.saturating_sub(1);
+    let end = (x + DOTS_PER_COLOR - 1).min(DOTS_PER_LINE);
 
-        tmp >>= 1;
-        *pixel = color;
+    let mut color_num = 0;
+    for (i, dot) in dots[start..end].iter().enumerate() {
+        color_num |= dot << ((start + i) % DOTS_PER_COLOR);
     }
 
-    pixel_map
+    palette[color_num as usize]
 }
 
 impl<'a> GraphicsHandler<'a> {
@@ -178,189 +474,360 @@ impl<'a> GraphicsHandler<'a> {
         }
     }
 
-    fn draw_char_block(&mut self, val: u8, block_idx: usize) {
+    // Each dot of a character is held for two dots of the signal
+    fn char_dots(&self, val: u8, row: usize, dots: &mut [u8]) {
         // Mask off the upper two bits as they don't affect address
         // Then multiply by 8 (since each character is represented by 8 bytes)
-        let char_addr = ((val & 0x3F) as u32 * BLOCK_HEIGHT) as usize;
-
-        // For every byte (row) in character map
-        let mut pbuf_idx = block_to_pbuf_idx(block_idx);
-        for j in char_addr..char_addr + BLOCK_HEIGHT as usize {
-            let mut idx = pbuf_idx;
-            let mut char_map = self.char_data[j];
-
-            // 7th bit tells us if in invert mode
-            // 6th bit tells us if in flash mode
-            // So invert bits if in invert mode, or in flash mode and invert_text is true
-            if (val & (1 << 7) == 0) && (val & (1 << 6) == 0 || self.flash) {
-                char_map ^= 0xFF; // Invert all bits
-            }
+        let char_addr = (val & 0x3F) as usize * BLOCK_HEIGHT as usize;
+        let mut char_map = match self.lowercase_adapter && val >= LOWERCASE_START {
+            true => self.lowercase_glyphs[(val - LOWERCASE_START) as usize][row],
+            false => self.char_data[char_addr + row],
+        };
 
-            // If in hires mode, convert text row into hires pixel mapping
-            let mut pixel_map: Option<[u32; BLOCK_WIDTH as usize]> = None;
-            if self.hires_mode {
-                let block_col = block_idx % BLOCK_COLS;
-
-                // Need to reverse the 6 LSBs because hires mode has things reversed
-                let mut reverse = 0;
-                for k in 0..7 {
-                    if (char_map & (1 << k)) != 0 {
-                        reverse |= 1 << (6 - k);
-                    }
-                }
+        // 7th bit tells us if in invert mode
+        // 6th bit tells us if in flash mode
+        // So invert bits if in invert mode, or in flash mode and invert_text is true
+        if (val & (1 << 7) == 0) && (val & (1 << 6) == 0 || self.flash) {
+            char_map ^= 0xFF; // Invert all bits
+        }
 
-                // Create this "buffer" because that's what pixel map expects for hires mode
-                let buffer = [0, reverse, 0];
-                pixel_map = Some(to_pixel_map(&buffer, 1, block_col));
-            } else {
-                char_map <<= 1; // Then shift off high bit because we don't need it
-            }
+        // The leftmost dot comes from bit 6
+        for (i, dot_pair) in dots.chunks_mut(2).enumerate() {
+            dot_pair.fill((char_map >> (6 - i)) & 1);
+        }
+    }
 
-            // For every dot in row of character map
-            for i in 0..BLOCK_WIDTH {
-                if let Some(pixel_map) = pixel_map {
-                    self.draw_pixel(pixel_map[i as usize], idx);
-                } else {
-                    let color = match char_map & (1 << 7) != 0 {
-                        true => color::WHITE,
-                        false => color::BLACK,
-                    };
-
-                    self.draw_pixel(color, idx);
-                    char_map <<= 1;
-                }
+    // Lores colors are made by repeating the 4 bits of a color number over the whole block
+    fn lores_dots(val: u8, row: usize, start_x: usize, dots: &mut [u8]) {
+        // Each nybble represents the top half and bottom half colors of a block
+        let color_num = match row < (BLOCK_HEIGHT / 2) as usize {
+            true => val & 0xF,
+            false => val >> 4,
+        };
 
-                idx += PIXEL_SIZE as usize;
-            }
+        for (i, dot) in dots.iter_mut().enumerate() {
+            *dot = (color_num >> ((start_x + i) % DOTS_PER_COLOR)) & 1;
+        }
+    }
+
+    /* Each hires bit (starting from the lowest) is held for two dots. If the high bit is set, the
+    whole byte is delayed by a dot, with the last dot of the previous byte held a little longer. */
+    fn hires_dots(val: u8, prev_dot: u8, dots: &mut [u8]) {
+        let delay = (val >> 7) as usize;
+        dots[0] = prev_dot;
 
-            pbuf_idx += BYTES_PER_BLOCK_ROW;
+        for (i, dot) in dots.iter_mut().enumerate().skip(delay) {
+            *dot = (val >> ((i - delay) / 2)) & 1;
         }
     }
 
-    fn draw_lores_block(&mut self, val: u8, block_idx: usize) {
-        let color_map = [
-            color::BLACK,
-            color::MAGENTA,
-            color::DARK_BLUE,
-            color::PURPLE,
-            color::DARK_GREEN,
-            color::GREY1,
-            color::BLUE,
-            color::LIGHT_BLUE,
-            color::BROWN,
-            color::ORANGE,
-            color::GREY2,
-            color::PINK,
-            color::LIGHT_GREEN,
-            color::YELLOW,
-            color::AQUA,
-            color::WHITE,
-        ];
+    // Builds the dots of the block the video scanner is fetching on the given line
+    fn build_block(&self, buffer: &[u8], line: usize, col: usize, dots: &mut [u8; DOTS_PER_LINE]) {
+        let block_row = line / BLOCK_HEIGHT as usize;
+        let row = line % BLOCK_HEIGHT as usize;
 
-        // Each nybble represents the top half and bottom half colors of a block
-        // A lookup table is used to map the nybble value to a color
-        let lower_color = color_map[(val >> 4) as usize];
-        let upper_color = color_map[(val & 0xF) as usize];
-
-        let mut pbuf_idx = block_to_pbuf_idx(block_idx);
-        for j in 0..BLOCK_HEIGHT {
-            let mut idx = pbuf_idx;
-
-            for _ in 0..BLOCK_WIDTH {
-                let color = match j < (BLOCK_HEIGHT / 2) {
-                    true => upper_color,
-                    false => lower_color,
-                };
+        let text_start = match self.use_pg2 {
+            true => 0x800,
+            false => 0x400,
+        };
+        let hires_start = match self.use_pg2 {
+            true => 0x4000,
+            false => 0x2000,
+        };
 
-                self.draw_pixel(color, idx);
-                idx += PIXEL_SIZE as usize;
+        // If in mixed mode, always draw characters in the last 4 block rows
+        let text_line = self.txt_mode || (block_row >= MIXED_TEXT_ROW && self.mixed_mode);
+
+        let start_x = col * DOTS_PER_BLOCK;
+        let prev_dot = match start_x > 0 {
+            true => dots[start_x - 1],
+            false => 0,
+        };
+        let block_dots = &mut dots[start_x..start_x + DOTS_PER_BLOCK];
+
+        match (text_line, self.hires_mode) {
+            (true, _) => {
+                let val = buffer[text_start + block_row_offset(block_row) + col];
+                self.char_dots(val, row, block_dots);
             }
+            (false, true) => {
+                let addr = hires_start + 0x400 * row + block_row_offset(block_row) + col;
+                GraphicsHandler::hires_dots(buffer[addr], prev_dot, block_dots);
+            }
+            (false, false) => {
+                let val = buffer[text_start + block_row_offset(block_row) + col];
+                GraphicsHandler::lores_dots(val, row, start_x, block_dots);
+            }
+        }
+    }
+
+    /* Moves the beam along by the given number of cycles, building each block of the display from
+    the video memory and soft switches as they are at the moment the beam reaches it. */
+    pub fn run_scanner(&mut self, cycles: u32, buffer: &[u8]) {
+        for _ in 0..cycles {
+            let line = self.scan_cycle / CYCLES_PER_LINE;
+            let h_cycle = self.scan_cycle % CYCLES_PER_LINE;
 
-            pbuf_idx += BYTES_PER_BLOCK_ROW;
+            if line < DISP_HEIGHT as usize && h_cycle >= HBLANK_CYCLES {
+                let col = h_cycle - HBLANK_CYCLES;
+
+                // The color burst is sent (or not) during the horizontal blank before the line
+                if col == 0 {
+                    self.color_burst[line] = !self.txt_mode;
+                }
+
+                let mut dots = self.lines[line];
+                self.build_block(buffer, line, col, &mut dots);
+                self.lines[line] = dots;
+            }
+
+            self.scan_cycle = (self.scan_cycle + 1) % CYCLES_PER_FRAME;
         }
     }
 
-    fn draw_hires_block(&mut self, buffer: &[u8], buf_idx: usize, block_idx: usize) {
-        let block_col = block_idx % BLOCK_COLS;
-        let mut pbuf_idx = block_to_pbuf_idx(block_idx);
+    fn draw_lines(&mut self) {
+        let mut colors = [0; DOTS_PER_LINE];
+
+        for line in 0..DISP_HEIGHT as usize {
+            // The color burst signal is turned off in text mode, so the monitor shows no color at all
+            let phosphor = match self.color_burst[line] {
+                true => self.monitor.phosphor(),
+                false => Some(self.monitor.phosphor().unwrap_or(color::WHITE)),
+            };
+
+            let dots = self.lines[line];
+            for (x, color) in colors.iter_mut().enumerate() {
+                *color = match (phosphor, dots[x]) {
+                    (None, _) => decode_color(&dots, x, &self.palettes[self.palette].colors),
+                    (Some(phosphor), 1) => phosphor,
+                    (Some(_), _) => color::BLACK,
+                };
+            }
 
-        for j in 0..BLOCK_HEIGHT {
-            let mut idx = pbuf_idx;
-            let pixel_map = to_pixel_map(buffer, buf_idx + (j as usize * 1024), block_col);
+            // Soften the edges of the dots a bit, like the limited bandwidth of a monitor does
+            if self.blur {
+                let sharp = colors;
+                for (x, color) in colors.iter_mut().enumerate() {
+                    let left = sharp[x.saturating_sub(1)];
+                    let right = sharp[(x + 1).min(DOTS_PER_LINE - 1)];
+                    *color = blend(left, sharp[x], right);
+                }
+            }
 
-            for i in 0..BLOCK_WIDTH {
-                self.draw_pixel(pixel_map[i as usize], idx);
+            let mut idx = line * ROWS_PER_LINE * BYTES_PER_LINE;
+            for &color in colors.iter() {
+                let gap_color = match self.scanlines {
+                    true => darken(color),
+                    false => color,
+                };
+
+                self.draw_pixel(color, idx);
+                self.draw_pixel(gap_color, idx + BYTES_PER_LINE);
                 idx += PIXEL_SIZE as usize;
             }
+        }
+    }
 
-            pbuf_idx += BYTES_PER_BLOCK_ROW;
+    // The size of the display at a scale of 1
+    fn base_size(&self) -> (u32, u32) {
+        match self.aspect_correct {
+            true => (ASPECT_WIDTH, DISP_HEIGHT),
+            false => (DISP_WIDTH, DISP_HEIGHT),
         }
     }
 
-    /* The Apple 2 video memory mapping is crazy (though it makes sense why it is
-    the way that it is). So don't blame me for this insanity! */
-    fn draw_blocks(&mut self, buffer: &[u8]) {
-        let lores_addrs = match self.use_pg2 {
-            true => [0x800, 0x828, 0x850],
-            false => [0x400, 0x428, 0x450],
-        };
-        let hires_addrs = match self.use_pg2 {
-            true => [0x4000, 0x4028, 0x4050],
-            false => [0x2000, 0x2028, 0x2050],
+    // Where the display goes in the window, centered with black borders around it
+    fn display_rect(&self) -> Rect {
+        let (window_width, window_height) = self.canvas.output_size().unwrap();
+        let (base_width, base_height) = self.base_size();
+
+        let (width, height) = match self.scale {
+            Scale::Integer(scale) => (base_width * scale, base_height * scale),
+            Scale::Fit => {
+                let scale = f64::min(
+                    window_width as f64 / base_width as f64,
+                    window_height as f64 / base_height as f64,
+                );
+                (
+                    (base_width as f64 * scale) as u32,
+                    (base_height as f64 * scale) as u32,
+                )
+            }
         };
 
-        let start_addrs = match self.hires_mode {
-            true => &hires_addrs,
-            false => &lores_addrs,
-        };
+        Rect::new(
+            (window_width as i32 - width as i32) / 2,
+            (window_height as i32 - height as i32) / 2,
+            width,
+            height,
+        )
+    }
 
-        // Regardless of gfx mode, we draw 7x8 pixel "blocks" one at a time
-        let mut block_idx = 0;
-        for (section, start) in start_addrs.iter().enumerate() {
-            for j in 0..(BLOCK_ROWS / 3) {
-                for i in 0..BLOCK_COLS {
-                    let offset = 0x80 * j + i;
-                    let idx = start + offset;
-                    let txt_idx = lores_addrs[section] + offset;
-                    let block_row = (section * 8) + j;
-
-                    // If in mixed mode, always draw characters in the last 4 block rows
-                    match self.txt_mode || (block_row >= 20 && self.mixed_mode) {
-                        true => self.draw_char_block(buffer[txt_idx], block_idx),
-                        false => match self.hires_mode {
-                            true => self.draw_hires_block(buffer, idx, block_idx),
-                            false => self.draw_lores_block(buffer[idx], block_idx),
-                        },
-                    }
-
-                    block_idx += 1;
-                }
-            }
+    // With an integer scale, the window is sized to fit the display exactly
+    fn resize_window(&mut self) {
+        if let (Scale::Integer(scale), false) = (self.scale, self.fullscreen) {
+            let (base_width, base_height) = self.base_size();
+            self.canvas
+                .window_mut()
+                .set_size(base_width * scale, base_height * scale)
+                .unwrap();
         }
     }
 
-    pub fn handle_gfx(&mut self, frame_rate: u32, buffer: &[u8]) {
-        self.draw_blocks(buffer);
+    pub fn handle_gfx(&mut self, frame_rate: u32) {
+        self.draw_lines();
 
         // Update canvas
         self.pixel_surface
-            .update(None, &self.pixel_buf, (DISP_WIDTH * PIXEL_SIZE) as usize)
+            .update(None, &self.pixel_buf, BYTES_PER_LINE)
+            .unwrap();
+        let display_rect = self.display_rect();
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        self.canvas
+            .copy(&self.pixel_surface, None, display_rect)
             .unwrap();
-        self.canvas.copy(&self.pixel_surface, None, None).unwrap();
         self.canvas.present();
 
         // Keep track when to "flash" text
         self.handle_flash(frame_rate);
     }
 
+    /* The display at its native 280x192 size, with each pixel the average of its two dots (and
+    the scanline gaps left out). */
+    pub fn native_pixels(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(DISP_WIDTH as usize * DISP_HEIGHT as usize * 3);
+
+        for row in self.pixel_buf.chunks(BYTES_PER_LINE).step_by(ROWS_PER_LINE) {
+            for dot_pair in row.chunks(2 * PIXEL_SIZE as usize) {
+                let (left, right) = dot_pair.split_at(PIXEL_SIZE as usize);
+                for (l, r) in left.iter().zip(right) {
+                    pixels.push(((*l as u16 + *r as u16) / 2) as u8);
+                }
+            }
+        }
+
+        pixels
+    }
+
+    // The display the way it is drawn in the window
+    fn scaled_pixels(&self, width: u32, height: u32) -> Vec<u8> {
+        let buf_height = DISP_HEIGHT as usize * ROWS_PER_LINE;
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+
+        for y in 0..height as usize {
+            let row = &self.pixel_buf[(y * buf_height / height as usize) * BYTES_PER_LINE..];
+            for x in 0..width as usize {
+                let idx = (x * DOTS_PER_LINE / width as usize) * PIXEL_SIZE as usize;
+                pixels.extend_from_slice(&row[idx..idx + PIXEL_SIZE as usize]);
+            }
+        }
+
+        pixels
+    }
+
+    // Saves what is on the display as a PNG, either at its native size or as shown in the window
+    pub fn screenshot(&self, file_path: &Path, scaled: bool) -> Result<(), &'static str> {
+        match scaled {
+            true => {
+                let display_rect = self.display_rect();
+                let (width, height) = (display_rect.width(), display_rect.height());
+                write_png(file_path, width, height, &self.scaled_pixels(width, height))
+            }
+            false => write_png(file_path, DISP_WIDTH, DISP_HEIGHT, &self.native_pixels()),
+        }
+    }
+
+    pub fn load_char_rom(&mut self, file_path: &Path) {
+        self.char_data = load_char_set(file_path);
+        self.lowercase_glyphs = lowercase_glyphs(&self.char_data);
+    }
+
+    pub fn set_lowercase_adapter(&mut self, lowercase_adapter: bool) {
+        self.lowercase_adapter = lowercase_adapter;
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+        self.resize_window();
+    }
+
+    pub fn next_scale(&mut self) -> Scale {
+        self.set_scale(self.scale.next());
+        self.scale
+    }
+
+    pub fn toggle_aspect_correct(&mut self) -> bool {
+        self.aspect_correct = !self.aspect_correct;
+        self.resize_window();
+        self.aspect_correct
+    }
+
+    pub fn toggle_scanlines(&mut self) -> bool {
+        self.scanlines = !self.scanlines;
+        self.scanlines
+    }
+
+    pub fn toggle_blur(&mut self) -> bool {
+        self.blur = !self.blur;
+        self.blur
+    }
+
+    pub fn toggle_fullscreen(&mut self) -> bool {
+        self.fullscreen = !self.fullscreen;
+        let fullscreen_type = match self.fullscreen {
+            true => FullscreenType::Desktop,
+            false => FullscreenType::Off,
+        };
+
+        self.canvas
+            .window_mut()
+            .set_fullscreen(fullscreen_type)
+            .unwrap();
+        self.resize_window();
+        self.fullscreen
+    }
+
+    pub fn video_scanner(&self) -> VideoScanner {
+        VideoScanner {
+            scan_cycle: self.scan_cycle,
+            txt_mode: self.txt_mode,
+            hires_mode: self.hires_mode,
+            mixed_mode: self.mixed_mode,
+            use_pg2: self.use_pg2,
+        }
+    }
+
+    pub fn next_monitor(&mut self) -> Monitor {
+        self.monitor = self.monitor.next();
+        self.monitor
+    }
+
+    pub fn next_palette(&mut self) -> &str {
+        self.palette = (self.palette + 1) % self.palettes.len();
+        &self.palettes[self.palette].name
+    }
+
+    // Picks one of the built-in palettes by name, or else loads a palette file
+    pub fn set_palette(&mut self, palette: &str) -> Result<(), &'static str> {
+        if let Some(idx) = self.palettes.iter().position(|p| p.name == palette) {
+            self.palette = idx;
+            return Ok(());
+        }
+
+        self.palettes.push(Palette::load(Path::new(palette))?);
+        self.palette = self.palettes.len() - 1;
+        Ok(())
+    }
+
     pub fn handle_soft_sw(&mut self, address: usize) {
         match address {
             soft_switch::GFX_MODE => {
                 self.txt_mode = false;
             }
             soft_switch::TXT_MODE => {
+                // HIRES is left alone, so switching back to graphics mid-frame keeps it
                 self.txt_mode = true;
-                self.hires_mode = false;
             }
             soft_switch::SINGLE_MODE => {
                 self.mixed_mode = false;
@@ -386,19 +853,42 @@ impl<'a> GraphicsHandler<'a> {
         canvas: &'a mut Canvas<Window>,
         texture_creator: &'a TextureCreator<WindowContext>,
     ) -> Self {
+        let char_data = load_char_set(Path::new(DEFAULT_CHAR_ROM));
+
         GraphicsHandler {
             canvas,
-            pixel_buf: [0; (DISP_WIDTH * DISP_HEIGHT * PIXEL_SIZE) as usize],
+            pixel_buf: vec![0; BYTES_PER_LINE * ROWS_PER_LINE * DISP_HEIGHT as usize],
             pixel_surface: texture_creator
-                .create_texture_static(PixelFormatEnum::RGB24, DISP_WIDTH, DISP_HEIGHT)
+                .create_texture_static(
+                    PixelFormatEnum::RGB24,
+                    DOTS_PER_LINE as u32,
+                    DISP_HEIGHT * ROWS_PER_LINE as u32,
+                )
                 .unwrap(),
-            char_data: load_char_set(),
+            lines: vec![[0; DOTS_PER_LINE]; DISP_HEIGHT as usize],
+            color_burst: vec![false; DISP_HEIGHT as usize],
+            scan_cycle: 0,
+            char_data,
+            lowercase_glyphs: lowercase_glyphs(&char_data),
+            lowercase_adapter: false,
             frame_count: 0,
             flash: false,
             txt_mode: true,
             hires_mode: false,
             mixed_mode: false,
             use_pg2: false,
+            monitor: Monitor::Color,
+            scale: Scale::Integer(DISP_SCALE),
+            aspect_correct: false,
+            scanlines: false,
+            blur: false,
+            fullscreen: false,
+            palettes: vec![
+                Palette::new("ntsc", color::NTSC_PALETTE),
+                Palette::new("applewin", color::APPLEWIN_PALETTE),
+                Palette::new("iigs", color::IIGS_PALETTE),
+            ],
+            palette: 0,
         }
     }
 }
@@ -0,0 +1,393 @@
+/*
+Mounts a directory on the host as a ProDOS disk. The files (and subdirectories) are copied into a
+freshly formatted 140K volume when the disk is inserted, and anything written to the volume is
+copied back out into the directory when the disk is saved.
+
+Each file is remembered as it was when it was last copied between the host and the volume, so only
+files changed by the Apple 2 get written back, and a host file that was changed in the meantime is
+never overwritten or deleted. While the drive is idle, changes made on the host rebuild the volume.
+
+The ProDOS file type and aux type (the load address of binary files) are kept in the host file
+name with a suffix like "#062000" (type $06, aux type $2000), the same convention CiderPress and
+other tools use. Files without one are treated as text files if they end in .txt, and as binary
+files loaded at $2000 otherwise.
+*/
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::prodos::{self, ProDosDisk};
+
+const NAME_LEN: usize = 15;
+const DEFAULT_VOLUME_NAME: &str = "HOST";
+const DEFAULT_BIN_ADDRESS: u16 = 0x2000;
+
+// How a host file appears on the ProDOS volume
+struct HostFile {
+    name: String,
+    file_type: u8,
+    aux_type: u16,
+    is_text: bool,
+}
+
+// A file as it was when it was last copied between the host and the volume
+#[derive(Clone)]
+struct Snapshot {
+    host_path: PathBuf,
+    host_hash: u64,
+    disk_hash: u64,
+}
+
+// The path, size and modification time of everything in a directory
+type DirScan = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+pub struct HostDir {
+    root: PathBuf,
+
+    // Which host file each file on the volume came from (or was last written to)
+    files: HashMap<String, Snapshot>,
+
+    // What the directory looked like the last time it was synced with the volume
+    scan: DirScan,
+}
+
+fn hash_data(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Changing the type of a file on the volume counts as changing the file
+fn disk_hash(file_type: u8, aux_type: u16, data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (file_type, aux_type, data).hash(&mut hasher);
+    hasher.finish()
+}
+
+// Whether the host file is the same as when it was copied (None if it no longer exists)
+fn unchanged_on_host(snapshot: &Snapshot) -> Option<bool> {
+    fs::read(&snapshot.host_path)
+        .ok()
+        .map(|data| hash_data(&data) == snapshot.host_hash)
+}
+
+fn scan_dir(dir: &Path, scan: &mut DirScan) {
+    let Ok(dir_entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut dir_entries: Vec<_> = dir_entries.filter_map(|entry| entry.ok()).collect();
+    dir_entries.sort_by_key(|entry| entry.file_name());
+
+    for dir_entry in dir_entries {
+        if dir_entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let entry_path = dir_entry.path();
+        let (len, modified) = match dir_entry.metadata() {
+            Ok(metadata) => (metadata.len(), metadata.modified().ok()),
+            Err(_) => (0, None),
+        };
+        scan.push((entry_path.clone(), len, modified));
+
+        if entry_path.is_dir() {
+            scan_dir(&entry_path, scan);
+        }
+    }
+}
+
+// Turns any name into a valid ProDOS name (a letter followed by letters, digits or periods)
+fn to_prodos_name(name: &str) -> String {
+    let mut prodos_name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '.',
+        })
+        .collect();
+
+    if !prodos_name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        prodos_name.insert(0, 'A');
+    }
+    prodos_name.truncate(NAME_LEN);
+    prodos_name
+}
+
+// Splits the "#TTAAAA" suffix off of a host file name
+fn parse_suffix(file_name: &str) -> Option<(&str, u8, u16)> {
+    let (name, suffix) = file_name.rsplit_once('#')?;
+    if suffix.len() != 6 || !suffix.is_ascii() {
+        return None;
+    }
+
+    let file_type = u8::from_str_radix(&suffix[..2], 16).ok()?;
+    let aux_type = u16::from_str_radix(&suffix[2..], 16).ok()?;
+    Some((name, file_type, aux_type))
+}
+
+fn host_file(file_name: &str) -> HostFile {
+    if let Some((name, file_type, aux_type)) = parse_suffix(file_name) {
+        return HostFile {
+            name: to_prodos_name(name),
+            file_type,
+            aux_type,
+            is_text: false,
+        };
+    }
+
+    let is_text = file_name.to_ascii_lowercase().ends_with(".txt");
+    let (file_type, aux_type) = match is_text {
+        true => (prodos::file_type::TXT, 0),
+        false => (prodos::file_type::BIN, DEFAULT_BIN_ADDRESS),
+    };
+
+    HostFile {
+        name: to_prodos_name(file_name),
+        file_type,
+        aux_type,
+        is_text,
+    }
+}
+
+impl HostDir {
+    // Builds a ProDOS volume from the directory, returning the blocks of the volume
+    pub fn mount(root: &Path) -> Result<(Self, Vec<u8>), &'static str> {
+        let dir_name = root
+            .canonicalize()
+            .ok()
+            .and_then(|root| {
+                root.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .unwrap_or_else(|| DEFAULT_VOLUME_NAME.to_string());
+
+        let mut disk = ProDosDisk::format(&to_prodos_name(&dir_name), prodos::FLOPPY_BLOCKS)?;
+        let mut host_dir = HostDir {
+            root: root.to_path_buf(),
+            files: HashMap::new(),
+            scan: Vec::new(),
+        };
+
+        host_dir.copy_in(&mut disk, root, "")?;
+        scan_dir(root, &mut host_dir.scan);
+        Ok((host_dir, disk.into_blocks()))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Checks for files added, changed or removed on the host since the volume was last synced
+    pub fn changed_on_host(&mut self) -> bool {
+        let mut scan = Vec::new();
+        scan_dir(&self.root, &mut scan);
+        if scan == self.scan {
+            return false;
+        }
+
+        self.scan = scan;
+        true
+    }
+
+    fn copy_in(
+        &mut self,
+        disk: &mut ProDosDisk,
+        host_path: &Path,
+        path: &str,
+    ) -> Result<(), &'static str> {
+        let mut dir_entries: Vec<_> = fs::read_dir(host_path)
+            .map_err(|_| "Failed to read host directory.")?
+            .filter_map(|entry| entry.ok())
+            .collect();
+        dir_entries.sort_by_key(|entry| entry.file_name());
+
+        for dir_entry in dir_entries {
+            let file_name = dir_entry.file_name().to_string_lossy().to_string();
+            let entry_path = dir_entry.path();
+
+            // Hidden files (like .DS_Store) don't belong on the disk
+            if file_name.starts_with('.') {
+                continue;
+            }
+
+            if entry_path.is_dir() {
+                let dir_path = format!("{path}{}", to_prodos_name(&file_name));
+                if disk.create_dir(&dir_path).is_err() {
+                    println!("Skipping {file_name}, a file with that name is already on the disk.");
+                    continue;
+                }
+
+                self.copy_in(disk, &entry_path, &format!("{dir_path}/"))?;
+                continue;
+            }
+
+            let file = host_file(&file_name);
+            let file_path = format!("{path}{}", file.name);
+            if self.files.contains_key(&file_path) {
+                println!("Skipping {file_name}, a file with that name is already on the disk.");
+                continue;
+            }
+
+            let host_data = fs::read(&entry_path).map_err(|_| "Failed to read host file.")?;
+            let host_hash = hash_data(&host_data);
+            let data = match file.is_text {
+                true => prodos::text_from_host(&host_data),
+                false => host_data,
+            };
+
+            disk.write_file(&file_path, file.file_type, file.aux_type, &data, false)
+                .map_err(|_| "Host directory does not fit on a 140K disk.")?;
+
+            let snapshot = Snapshot {
+                host_path: entry_path,
+                host_hash,
+                disk_hash: disk_hash(file.file_type, file.aux_type, &data),
+            };
+            self.files.insert(file_path, snapshot);
+        }
+
+        Ok(())
+    }
+
+    // Copies the files changed on the volume back out to the directory, which then mirrors it
+    pub fn write_back(&mut self, blocks: Vec<u8>) -> Result<(), &'static str> {
+        let disk = ProDosDisk::new(blocks)?;
+        let mut files = HashMap::new();
+        self.copy_out(&disk, &self.root.clone(), "", &mut files)?;
+
+        // Files deleted from the disk get deleted from the directory too, unless changed on the host
+        for snapshot in self.files.values() {
+            if files
+                .values()
+                .any(|kept| kept.host_path == snapshot.host_path)
+            {
+                continue;
+            }
+
+            match unchanged_on_host(snapshot) {
+                Some(true) => {
+                    fs::remove_file(&snapshot.host_path)
+                        .map_err(|_| "Failed to delete host file.")?;
+                }
+                Some(false) => println!(
+                    "{} was changed on the host, so it was not deleted.",
+                    snapshot.host_path.display()
+                ),
+                None => {}
+            }
+        }
+
+        self.files = files;
+
+        // Changes just written out aren't changes made on the host
+        self.scan.clear();
+        scan_dir(&self.root, &mut self.scan);
+        Ok(())
+    }
+
+    fn copy_out(
+        &self,
+        disk: &ProDosDisk,
+        host_path: &Path,
+        path: &str,
+        files: &mut HashMap<String, Snapshot>,
+    ) -> Result<(), &'static str> {
+        for entry in disk.list_dir(path)? {
+            let entry_path = format!("{path}{}", entry.name);
+
+            if entry.is_dir() {
+                let dir_path = host_path.join(&entry.name);
+                let dir_path = match self.find_host_dir(host_path, &entry.name) {
+                    Some(existing) => existing,
+                    None => {
+                        fs::create_dir(&dir_path)
+                            .map_err(|_| "Failed to create host directory.")?;
+                        dir_path
+                    }
+                };
+
+                self.copy_out(disk, &dir_path, &format!("{entry_path}/"), files)?;
+                continue;
+            }
+
+            let (_, data) = disk.read_file(&entry_path)?;
+            let disk_hash = disk_hash(entry.file_type, entry.aux_type, &data);
+            let snapshot = self.files.get(&entry_path);
+
+            // Leave files the Apple 2 didn't change alone
+            if let Some(snapshot) = snapshot.filter(|snapshot| snapshot.disk_hash == disk_hash) {
+                files.insert(entry_path, snapshot.clone());
+                continue;
+            }
+
+            // Keep using the host file the disk file came from, as long as its type didn't change
+            let host_file_path = snapshot
+                .map(|snapshot| &snapshot.host_path)
+                .filter(|host_file_path| {
+                    let file_name = host_file_path.file_name().unwrap().to_string_lossy();
+                    let file = host_file(&file_name);
+                    file.file_type == entry.file_type && file.aux_type == entry.aux_type
+                })
+                .cloned()
+                .unwrap_or_else(|| {
+                    host_path.join(format!(
+                        "{}#{:02X}{:04X}",
+                        entry.name, entry.file_type, entry.aux_type
+                    ))
+                });
+
+            // Never overwrite a host file that changed (or appeared) since the disk was inserted
+            let safe = match snapshot {
+                Some(snapshot) if snapshot.host_path == host_file_path => {
+                    unchanged_on_host(snapshot) == Some(true)
+                }
+                _ => !host_file_path.exists(),
+            };
+            if !safe {
+                println!(
+                    "{} was changed on the host, so changes made to it on the disk were not saved.",
+                    host_file_path.display()
+                );
+                if let Some(snapshot) = snapshot {
+                    files.insert(entry_path, snapshot.clone());
+                }
+                continue;
+            }
+
+            let is_text = host_file(&host_file_path.file_name().unwrap().to_string_lossy()).is_text;
+            let host_data = match is_text {
+                true => prodos::text_to_host(&data),
+                false => data,
+            };
+
+            fs::write(&host_file_path, &host_data).map_err(|_| "Failed to write host file.")?;
+            let snapshot = Snapshot {
+                host_path: host_file_path,
+                host_hash: hash_data(&host_data),
+                disk_hash,
+            };
+            files.insert(entry_path, snapshot);
+        }
+
+        Ok(())
+    }
+
+    // Finds the host directory a ProDOS directory was made from, since its name may be different
+    fn find_host_dir(&self, host_path: &Path, name: &str) -> Option<PathBuf> {
+        fs::read_dir(host_path)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|entry_path| {
+                entry_path.is_dir()
+                    && entry_path.file_name().is_some_and(|file_name| {
+                        to_prodos_name(&file_name.to_string_lossy()) == name
+                    })
+            })
+    }
+}
@@ -0,0 +1,15 @@
+/*
+Everything to do with disk images lives in this library so it can be shared by the emulator and
+the a2disk tool, which works with disk images without starting the emulator.
+*/
+
+pub mod archive;
+pub mod basic;
+pub mod dos33;
+pub mod dsk2woz;
+pub mod host_dir;
+pub mod nib2woz;
+pub mod prodos;
+pub mod twomg;
+pub mod wizard_of_woz;
+pub mod woz2dsk;
@@ -1,26 +1,186 @@
 mod apple2;
 mod disk_controller;
-mod dsk2woz;
 mod graphics;
 mod mem_manager;
+mod recorder;
 mod sound;
-mod wizard_of_woz;
 
-use apple2::Apple2;
+use apple2::{Apple2, DiskRom};
+use graphics::Scale;
 use mem_manager::MemManager;
+use rust_apple2::archive;
+use rust_apple2::wizard_of_woz::BlankFormat;
 
 use std::cell::RefCell;
+use std::io::Write;
+use std::path::Path;
 use std::rc::Rc;
-use std::time::{Duration, Instant};
 
 use sdl2::event::Event;
 use sdl2::keyboard::{Keycode, Mod};
 use sdl2::EventPump;
 
-const FRAME_RATE: u32 = 60;
-const US_PER_FRAME: u64 = 1000000 / FRAME_RATE as u64;
+const NUM_DRIVES: usize = 2;
 
-fn handle_input(apple2: &mut Apple2, event_pump: &mut EventPump) -> bool {
+struct Options {
+    disk_files: [Option<String>; NUM_DRIVES],
+    archive_indexes: [Option<usize>; NUM_DRIVES],
+    blank_formats: [Option<BlankFormat>; NUM_DRIVES],
+    disk_rom: Option<DiskRom>,
+    palette: Option<String>,
+    scale: Option<Scale>,
+    record_dir: Option<String>,
+    char_rom: Option<String>,
+    lowercase_adapter: bool,
+    lowercase_keys: bool,
+    vsync: bool,
+}
+
+fn parse_index(arg: Option<String>) -> usize {
+    match arg.and_then(|n| n.parse::<usize>().ok()) {
+        Some(n) if n > 0 => n - 1,
+        _ => panic!("Archive index must be a number starting at 1!"),
+    }
+}
+
+fn parse_blank_format(arg: Option<String>) -> BlankFormat {
+    match arg.as_deref() {
+        Some("dos") => BlankFormat::Dos33,
+        Some("prodos") => BlankFormat::ProDos,
+        Some("unformatted") => BlankFormat::Unformatted,
+        _ => panic!("Blank disk format must be dos, prodos or unformatted!"),
+    }
+}
+
+fn parse_scale(arg: Option<String>) -> Scale {
+    match arg.as_deref() {
+        Some("fit") => Scale::Fit,
+        Some(scale) => match scale.parse::<u32>() {
+            Ok(n) if (1..=graphics::MAX_SCALE).contains(&n) => Scale::Integer(n),
+            _ => panic!(
+                "Scale must be fit or a number from 1 to {}!",
+                graphics::MAX_SCALE
+            ),
+        },
+        None => panic!(
+            "Scale must be fit or a number from 1 to {}!",
+            graphics::MAX_SCALE
+        ),
+    }
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        disk_files: [None, None],
+        archive_indexes: [None, None],
+        blank_formats: [None, None],
+        disk_rom: None,
+        palette: None,
+        scale: None,
+        record_dir: None,
+        char_rom: None,
+        lowercase_adapter: false,
+        lowercase_keys: false,
+        vsync: false,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disk-rom" => {
+                options.disk_rom = match args.next().as_deref() {
+                    Some("16") => Some(DiskRom::Sector16),
+                    Some("13") => Some(DiskRom::Sector13),
+                    _ => panic!("Disk ROM must be either 16 or 13!"),
+                };
+            }
+            "--palette" => {
+                options.palette = match args.next() {
+                    Some(palette) => Some(palette),
+                    None => panic!("Palette must be ntsc, applewin, iigs or a palette file!"),
+                };
+            }
+            "--scale" => {
+                options.scale = Some(parse_scale(args.next()));
+            }
+            "--record" => {
+                options.record_dir = match args.next() {
+                    Some(dir) => Some(dir),
+                    None => panic!("Recording needs a directory to be saved to!"),
+                };
+            }
+            "--char-rom" => {
+                options.char_rom = match args.next() {
+                    Some(char_rom) => Some(char_rom),
+                    None => panic!("No character ROM file given!"),
+                };
+            }
+            "--lowercase" => {
+                options.lowercase_adapter = true;
+            }
+            "--lowercase-keys" => {
+                options.lowercase_keys = true;
+            }
+            "--vsync" => {
+                options.vsync = true;
+            }
+            "--index1" => {
+                options.archive_indexes[0] = Some(parse_index(args.next()));
+            }
+            "--index2" => {
+                options.archive_indexes[1] = Some(parse_index(args.next()));
+            }
+            "--new1" => {
+                options.blank_formats[0] = Some(parse_blank_format(args.next()));
+            }
+            "--new2" => {
+                options.blank_formats[1] = Some(parse_blank_format(args.next()));
+            }
+            _ => match options.disk_files.iter_mut().find(|f| f.is_none()) {
+                Some(disk_file) => *disk_file = Some(arg),
+                None => panic!("Only two disks can be inserted!"),
+            },
+        }
+    }
+
+    options
+}
+
+// If an archive holds several disk images, ask which one goes in the drive
+fn choose_image(drive: usize, disk_file: &str) -> usize {
+    let file_path = Path::new(disk_file);
+    if !archive::is_zip(file_path) {
+        return 0;
+    }
+
+    // A broken archive gets reported when the disk is inserted
+    let images = match archive::list_images(file_path) {
+        Ok(images) if images.len() > 1 => images,
+        _ => return 0,
+    };
+
+    println!("{disk_file} contains several disk images:");
+    for (i, image) in images.iter().enumerate() {
+        println!("  {}: {image}", i + 1);
+    }
+
+    loop {
+        print!("Choose an image for drive {drive}: ");
+        std::io::stdout().flush().expect("Failed to flush stdout!");
+
+        let mut choice = String::new();
+        std::io::stdin()
+            .read_line(&mut choice)
+            .expect("Failed to read choice!");
+
+        match choice.trim().parse::<usize>() {
+            Ok(n) if n > 0 && n <= images.len() => return n - 1,
+            _ => println!("Please enter a number from 1 to {}.", images.len()),
+        }
+    }
+}
+
+fn handle_input(apple2: &mut Apple2, event_pump: &mut EventPump, lowercase_keys: bool) -> bool {
     // TODO: Escape keys, and will need to change key for reset()
 
     for event in event_pump.poll_iter() {
@@ -34,6 +194,78 @@ fn handle_input(apple2: &mut Apple2, event_pump: &mut EventPump) -> bool {
             } => {
                 apple2.reset();
             }
+            Event::KeyDown {
+                keycode: Some(Keycode::F2),
+                ..
+            } => {
+                apple2.next_monitor();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F3),
+                ..
+            } => {
+                apple2.next_palette();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F4),
+                ..
+            } => {
+                apple2.next_scale();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F5),
+                ..
+            } => {
+                apple2.toggle_aspect_correct();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F6),
+                ..
+            } => {
+                apple2.toggle_scanlines();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F7),
+                ..
+            } => {
+                apple2.toggle_blur();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F11),
+                ..
+            } => {
+                apple2.toggle_fullscreen();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F8),
+                keymod,
+                ..
+            } => {
+                // Shift makes a ProDOS disk instead, and Ctrl one that still needs to be formatted
+                let format = match (
+                    keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+                    keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD),
+                ) {
+                    (_, true) => BlankFormat::Unformatted,
+                    (true, false) => BlankFormat::ProDos,
+                    (false, false) => BlankFormat::Dos33,
+                };
+                apple2.new_blank_disk(format);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F9),
+                ..
+            } => {
+                apple2.toggle_recording();
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F12),
+                keymod,
+                ..
+            } => {
+                // Holding shift saves the screenshot at the size it is shown in the window
+                apple2.take_screenshot(keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD));
+            }
             Event::KeyDown {
                 keycode: Some(keycode),
                 keymod,
@@ -48,24 +280,28 @@ fn handle_input(apple2: &mut Apple2, event_pump: &mut EventPump) -> bool {
                     continue;
                 }
 
-                // Convert lowercase to uppercase
+                let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                let ctrl = keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD);
+                let caps = keymod.contains(Mod::CAPSMOD);
+
+                // Convert lowercase to uppercase (unless lowercase is passed through to the Apple 2)
                 let mut ascii = keycode as u8;
-                if ascii.is_ascii_lowercase() {
+                if ascii.is_ascii_lowercase() && (!lowercase_keys || shift || ctrl || caps) {
                     ascii -= 32;
                 }
 
                 // Get the proper ASCII character if shift held
-                if keymod.contains(Mod::LSHIFTMOD) || keymod.contains(Mod::RSHIFTMOD) {
-                    ascii = Apple2::get_shift_ascii(ascii);
+                if shift {
+                    ascii = Apple2::get_shift_ascii(ascii, lowercase_keys);
                 }
 
                 // Do nothing if not a valid Apple 2 key
-                if !Apple2::is_valid_key(ascii) {
+                if !Apple2::is_valid_key(ascii, lowercase_keys) {
                     continue;
                 }
 
                 // Modify the value (if necessary) when CTRL is held
-                if keymod.contains(Mod::LCTRLMOD) || keymod.contains(Mod::RCTRLMOD) {
+                if ctrl {
                     ascii = Apple2::get_ctrl_ascii(ascii);
                 }
 
@@ -80,7 +316,7 @@ fn handle_input(apple2: &mut Apple2, event_pump: &mut EventPump) -> bool {
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let options = parse_args();
 
     // Initialize SDL
     let sdl_context = sdl2::init().unwrap();
@@ -97,9 +333,13 @@ fn main() {
             graphics::DISP_HEIGHT * graphics::DISP_SCALE,
         )
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
-    let mut canvas = window.into_canvas().build().unwrap();
+    let mut canvas = match options.vsync {
+        true => window.into_canvas().present_vsync().build().unwrap(),
+        false => window.into_canvas().build().unwrap(),
+    };
     let texture_creator = canvas.texture_creator();
 
     // Initialize Apple 2 emulator and insert disks
@@ -110,28 +350,57 @@ fn main() {
     let mut apple2 = Apple2::new(&mem_manager, &sdl_context, &mut canvas, &texture_creator);
     apple2.init();
 
-    if args.len() > 1 {
-        let disk_file = &args[1];
-        apple2.insert_disk(disk_file);
+    // An explicitly chosen Disk II ROM overrides the one picked for each inserted disk
+    if let Some(disk_rom) = options.disk_rom {
+        apple2.set_disk_rom(disk_rom);
+    }
+
+    for (i, disk_file) in options.disk_files.iter().enumerate() {
+        match (disk_file, options.blank_formats[i]) {
+            // Never overwrite an existing image with a blank disk
+            (Some(disk_file), Some(_)) if Path::new(disk_file).exists() => {
+                panic!("{disk_file} already exists!")
+            }
+            (Some(disk_file), Some(format)) => apple2.insert_blank_disk(i + 1, disk_file, format),
+            (Some(disk_file), None) => {
+                let archive_index =
+                    options.archive_indexes[i].unwrap_or_else(|| choose_image(i + 1, disk_file));
+                apple2.insert_disk(i + 1, disk_file, archive_index);
+            }
+            (None, Some(_)) => panic!("A new disk needs a file name to be saved to!"),
+            (None, None) => {}
+        }
+    }
+
+    if let Some(char_rom) = &options.char_rom {
+        apple2.load_char_rom(char_rom);
+    }
+    apple2.set_lowercase_adapter(options.lowercase_adapter);
+
+    if let Some(palette) = &options.palette {
+        apple2.set_palette(palette);
+    }
+    if let Some(scale) = options.scale {
+        apple2.set_scale(scale);
+    }
+    if let Some(record_dir) = &options.record_dir {
+        apple2.start_recording(Path::new(record_dir));
     }
 
     // Main loop
     loop {
-        apple2.draw_frame(FRAME_RATE);
-        if !handle_input(&mut apple2, &mut event_pump) {
+        apple2.draw_frame();
+        if !handle_input(&mut apple2, &mut event_pump, options.lowercase_keys) {
             break;
         }
 
-        let start_time = Instant::now();
+        apple2.run_frame();
 
-        apple2.run_frame(FRAME_RATE);
-
-        // Sleep for rest of frame period
-        let elapsed = Duration::from_micros(start_time.elapsed().as_micros() as u64);
-        let frame = Duration::from_micros(US_PER_FRAME);
-        if frame > elapsed {
-            let duration = frame - elapsed;
-            std::thread::sleep(duration);
-        }
+        // The sound sets the pace (even with vsync, which only keeps frames from tearing)
+        apple2.wait_for_audio();
     }
+
+    // Don't lose anything written to the disk
+    apple2.stop_recording();
+    apple2.save_disk();
 }
@@ -1,3 +1,5 @@
+use crate::graphics::VideoScanner;
+
 const MEM_SIZE: usize = 0x10000;
 const ROM_START: usize = 0xC000;
 const BANK_RAM_START: usize = 0xD000;
@@ -7,6 +9,16 @@ const EXT_RAM_SIZE: usize = 0x2000;
 
 const WRITE_EN_COUNT_MAX: u8 = 1;
 
+const NUM_SLOTS: usize = 8;
+const KEYBOARD_END: usize = 0xC00F;
+const INPUTS_START: usize = 0xC060;
+const INPUTS_END: usize = 0xC06F;
+const SLOT_IO_START: usize = 0xC080;
+const SLOT_IO_END: usize = 0xC0FF;
+const SLOT_ROM_START: usize = 0xC100;
+const SLOT_ROM_END: usize = 0xC7FF;
+const EXPANSION_ROM_END: usize = 0xCFFF;
+
 mod soft_switch {
     pub const BANK2_RAM_READ_NO_WRITE: usize = 0xC080;
     pub const BANK2_ROM_READ_WRITE: usize = 0xC081;
@@ -31,6 +43,7 @@ mod soft_switch {
 pub struct Cycle {
     pub address: usize,
     pub ctype: String,
+    pub value: u8,
 }
 
 pub struct MemManager {
@@ -43,6 +56,10 @@ pub struct MemManager {
     ram_write: bool,
     write_en_count: u8,
 
+    // Which slots have a card that answers reads of its I/O and ROM space
+    cards: [bool; NUM_SLOTS],
+    pub video_scanner: VideoScanner,
+
     pub cycles: Vec<Cycle>,
 }
 
@@ -57,13 +74,37 @@ impl MemManager {
             rom_read: true,
             ram_write: true,
             write_en_count: WRITE_EN_COUNT_MAX,
+            cards: [false; NUM_SLOTS],
+            video_scanner: VideoScanner::default(),
             cycles: Vec::new(),
         }
     }
 
+    /* Nothing drives the data bus when reading a soft switch (or an empty slot), so the CPU
+    just gets whatever byte the video scanner fetched during that cycle. The game inputs only
+    drive the high bit. */
+    fn floating_bus_read(&self, address: usize) -> u8 {
+        let floating_value = self.memory[self.video_scanner.fetch_address(self.cycles.len())];
+
+        match address {
+            ROM_START..=KEYBOARD_END => self.memory[address],
+            INPUTS_START..=INPUTS_END => (self.memory[address] & 0x80) | (floating_value & 0x7F),
+            SLOT_IO_START..=SLOT_IO_END if self.cards[(address >> 4) & 0x07] => {
+                self.memory[address]
+            }
+            SLOT_ROM_START..=SLOT_ROM_END if self.cards[(address >> 8) & 0x07] => {
+                self.memory[address]
+            }
+            _ => floating_value,
+        }
+    }
+
     // These are used by the CPU
     pub fn mem_read(&mut self, address: usize) -> u8 {
         let value = match address < BANK_RAM_START || self.rom_read {
+            true if (ROM_START..=EXPANSION_ROM_END).contains(&address) => {
+                self.floating_bus_read(address)
+            }
             true => self.memory[address],
 
             false => match address < EXT_RAM_START {
@@ -79,6 +120,7 @@ impl MemManager {
         self.cycles.push(Cycle {
             address,
             ctype: "read".to_string(),
+            value,
         });
 
         value
@@ -88,6 +130,7 @@ impl MemManager {
         self.cycles.push(Cycle {
             address,
             ctype: "write".to_string(),
+            value,
         });
 
         if address < ROM_START {
@@ -106,6 +149,10 @@ impl MemManager {
     }
 
     // Used by the Apple 2 emulator
+    pub fn insert_card(&mut self, slot: usize) {
+        self.cards[slot] = true;
+    }
+
     pub fn reset(&mut self) {
         self.bank2_active = true;
         self.rom_read = true;
@@ -0,0 +1,148 @@
+/*
+Converts a NIB image (the raw disk bytes of each track) into WOZ tracks and back again.
+NIB images don't store the extra zero bits of self-sync bytes, so these are added back in for
+any run of 0xFF bytes.
+
+NIB Reference: https://www.discferret.com/wiki/Apple_DOS_NIB_disk_format
+*/
+
+use crate::wizard_of_woz::WozTrack;
+
+const NUM_TRACKS: usize = 35;
+const BYTES_PER_TRACK: usize = 6656;
+pub const NIB_IMG_SIZE: usize = NUM_TRACKS * BYTES_PER_TRACK;
+
+const SYNC_BYTE: u8 = 0xFF;
+
+fn write_bit(data: &mut [u8], bit_pntr: &mut usize, bit: u8) {
+    let byte_idx = *bit_pntr / 8;
+    let bit_on = *bit_pntr % 8;
+    data[byte_idx] |= bit << (7 - bit_on);
+    *bit_pntr += 1;
+}
+
+fn write_byte(data: &mut [u8], bit_pntr: &mut usize, mut byte: u8) {
+    for _ in 0..8 {
+        let bit = byte >> 7;
+        byte <<= 1;
+        write_bit(data, bit_pntr, bit);
+    }
+}
+
+fn is_sync(nib: &[u8], idx: usize) -> bool {
+    // A sync byte is any 0xFF that neighbors another 0xFF (remembering the track is a circle)
+    let prev = nib[(idx + nib.len() - 1) % nib.len()];
+    let next = nib[(idx + 1) % nib.len()];
+    nib[idx] == SYNC_BYTE && (prev == SYNC_BYTE || next == SYNC_BYTE)
+}
+
+fn convert_track(nib: &[u8]) -> WozTrack {
+    // Worst case every byte is a 10-bit sync byte
+    let mut data = vec![0; (BYTES_PER_TRACK * 10).div_ceil(8)];
+    let mut bit_pntr = 0;
+
+    for (i, byte) in nib.iter().enumerate() {
+        write_byte(&mut data, &mut bit_pntr, *byte);
+
+        if is_sync(nib, i) {
+            bit_pntr += 2; // To account for trailing zeroes of this 10-bit byte
+        }
+    }
+
+    data.truncate(bit_pntr.div_ceil(8));
+    WozTrack {
+        bit_count: bit_pntr as u32,
+        data,
+    }
+}
+
+// Fills the buffer with the disk bytes of a track as the disk controller would read them
+pub fn read_track(track: &WozTrack, nib: &mut [u8]) {
+    // An empty track is unformatted, so it just stays zero-filled
+    if track.bit_count == 0 {
+        return;
+    }
+
+    let mut data_reg: u8 = 0;
+    let mut bit_pntr = 0;
+    let mut nib_idx = 0;
+    let mut bits_since_byte = 0;
+
+    /* Shift bits in just like the disk controller would, wrapping around the track if it doesn't
+    contain enough disk bytes to fill the buffer */
+    while nib_idx < nib.len() {
+        let byte = track.data[bit_pntr / 8];
+        let bit = (byte >> (7 - (bit_pntr % 8))) & 1;
+
+        data_reg <<= 1;
+        data_reg |= bit;
+        if data_reg & (1 << 7) != 0 {
+            nib[nib_idx] = data_reg;
+            nib_idx += 1;
+            data_reg = 0;
+            bits_since_byte = 0;
+        }
+
+        // A full revolution without a single disk byte means the track is unformatted
+        bits_since_byte += 1;
+        if bits_since_byte > track.bit_count {
+            break;
+        }
+
+        bit_pntr += 1;
+        bit_pntr %= track.bit_count as usize;
+    }
+}
+
+pub fn convert(nib: &[u8]) -> Vec<WozTrack> {
+    nib[..NIB_IMG_SIZE]
+        .chunks(BYTES_PER_TRACK)
+        .map(convert_track)
+        .collect()
+}
+
+pub fn to_nib(tracks: &[WozTrack]) -> Vec<u8> {
+    let mut nib = vec![0; NIB_IMG_SIZE];
+
+    for (track, nib_track) in tracks.iter().zip(nib.chunks_mut(BYTES_PER_TRACK)) {
+        read_track(track, nib_track);
+    }
+
+    nib
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsk2woz::{self, SectorFormat};
+    use crate::wizard_of_woz::WozImage;
+    use crate::woz2dsk;
+
+    #[test]
+    fn nib_round_trip() {
+        let dsk: Vec<u8> = (0..SectorFormat::Dos33.image_size())
+            .map(|i| (i * 3 + i / 256) as u8)
+            .collect();
+        let woz_image =
+            WozImage::from_sectors(&dsk, SectorFormat::Dos33, dsk2woz::DEFAULT_VOLUME).unwrap();
+
+        let nib = to_nib(&woz_image.tracks);
+        let tracks = convert(&nib);
+        assert_eq!(to_nib(&tracks), nib);
+        assert_eq!(woz2dsk::convert(&tracks, SectorFormat::Dos33).unwrap(), dsk);
+    }
+
+    #[test]
+    fn unformatted_track() {
+        let tracks: Vec<WozTrack> = (0..NUM_TRACKS)
+            .map(|_| WozTrack {
+                bit_count: 0,
+                data: Vec::new(),
+            })
+            .collect();
+
+        let nib = to_nib(&tracks);
+        assert_eq!(nib.len(), NIB_IMG_SIZE);
+        assert!(nib.iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,848 @@
+/*
+A ProDOS filesystem on top of the raw blocks of a volume (in ProDOS order, just like a PO image).
+Can walk directories, read and write files, create directories and format blank volumes.
+
+The volume directory starts at block 2 and is a chain of blocks holding 13 entries each, with the
+first entry being a header describing the volume (or subdirectory). Files are stored as either a
+seedling (a single data block), a sapling (an index block pointing to up to 256 data blocks) or a
+tree (a master index block pointing to up to 128 index blocks).
+
+Reference: Beneath Apple ProDOS, chapter 4
+*/
+
+const BLOCK_SIZE: usize = 512;
+
+// A 5.25" floppy holds 280 blocks
+pub const FLOPPY_BLOCKS: u16 = 280;
+
+const VOLUME_DIR_BLOCK: u16 = 2;
+const VOLUME_DIR_BLOCKS: u16 = 4;
+const BITMAP_BLOCK: u16 = 6;
+
+const ENTRY_LENGTH: usize = 0x27;
+const ENTRIES_PER_BLOCK: usize = 0x0D;
+const ENTRIES_START: usize = 0x04;
+const NAME_LEN: usize = 15;
+
+const POINTERS_PER_INDEX: usize = 256;
+const INDEXES_PER_MASTER: usize = 128;
+
+// Stops a corrupt disk from sending us around a chain of blocks forever
+const MAX_CHAIN: usize = 1024;
+
+mod storage_type {
+    pub const DELETED: u8 = 0x0;
+    pub const SEEDLING: u8 = 0x1;
+    pub const SAPLING: u8 = 0x2;
+    pub const TREE: u8 = 0x3;
+    pub const SUBDIRECTORY: u8 = 0xD;
+    pub const SUBDIRECTORY_HEADER: u8 = 0xE;
+    pub const VOLUME_HEADER: u8 = 0xF;
+}
+
+// Offsets into a directory entry
+mod entry {
+    pub const STORAGE_TYPE: usize = 0x00;
+    pub const NAME: usize = 0x01;
+    pub const FILE_TYPE: usize = 0x10;
+    pub const KEY_POINTER: usize = 0x11;
+    pub const BLOCKS_USED: usize = 0x13;
+    pub const EOF: usize = 0x15;
+    pub const ACCESS: usize = 0x1E;
+    pub const AUX_TYPE: usize = 0x1F;
+    pub const HEADER_POINTER: usize = 0x25;
+}
+
+// Offsets into a volume or subdirectory header
+mod header {
+    pub const RESERVED: usize = 0x10;
+    pub const ACCESS: usize = 0x1E;
+    pub const ENTRY_LENGTH: usize = 0x1F;
+    pub const ENTRIES_PER_BLOCK: usize = 0x20;
+    pub const FILE_COUNT: usize = 0x21;
+    pub const BITMAP_POINTER: usize = 0x23;
+    pub const TOTAL_BLOCKS: usize = 0x25;
+    pub const PARENT_POINTER: usize = 0x23;
+    pub const PARENT_ENTRY_NUMBER: usize = 0x25;
+    pub const PARENT_ENTRY_LENGTH: usize = 0x26;
+}
+
+mod access {
+    pub const UNLOCKED: u8 = 0xE3; // Destroy, rename, backup, write and read
+    pub const LOCKED: u8 = 0x21; // Backup and read
+    pub const WRITE: u8 = 0x02;
+}
+
+// Subdirectory headers must have this value in their first reserved byte
+const SUBDIRECTORY_MAGIC: u8 = 0x75;
+
+pub mod file_type {
+    pub const TXT: u8 = 0x04;
+    pub const BIN: u8 = 0x06;
+    pub const DIR: u8 = 0x0F;
+    pub const INT: u8 = 0xFA;
+    pub const BAS: u8 = 0xFC;
+    pub const VAR: u8 = 0xFD;
+    pub const REL: u8 = 0xFE;
+    pub const SYS: u8 = 0xFF;
+}
+
+const FILE_TYPE_NAMES: [(u8, &str); 8] = [
+    (file_type::TXT, "TXT"),
+    (file_type::BIN, "BIN"),
+    (file_type::DIR, "DIR"),
+    (file_type::INT, "INT"),
+    (file_type::BAS, "BAS"),
+    (file_type::VAR, "VAR"),
+    (file_type::REL, "REL"),
+    (file_type::SYS, "SYS"),
+];
+
+// Applesoft programs are loaded here, which is stored as their aux type
+pub const BAS_ADDRESS: u16 = 0x0801;
+
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: u8,
+    pub aux_type: u16,
+    pub eof: u32,
+    pub blocks_used: u16,
+    pub locked: bool,
+    storage_type: u8,
+    key_pointer: u16,
+    entry_idx: usize,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.storage_type == storage_type::SUBDIRECTORY
+    }
+}
+
+pub struct ProDosDisk {
+    blocks: Vec<u8>,
+}
+
+fn get_bytes_2(buf: &[u8], start: usize) -> u16 {
+    u16::from_le_bytes(buf[start..start + 2].try_into().unwrap())
+}
+
+fn put_bytes_2(value: u16, buf: &mut [u8], start: usize) {
+    buf[start..start + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+// The short name shown by CATALOG, or the type number for anything less common
+pub fn type_name(file_type: u8) -> String {
+    match FILE_TYPE_NAMES.iter().find(|(t, _)| *t == file_type) {
+        Some((_, name)) => name.to_string(),
+        None => format!("${file_type:02X}"),
+    }
+}
+
+pub fn type_from_name(name: &str) -> Option<u8> {
+    let name = name.to_ascii_uppercase();
+    match name.strip_prefix('$') {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => FILE_TYPE_NAMES
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(t, _)| *t),
+    }
+}
+
+// Text files simply have carriage returns ending each line
+pub fn text_to_host(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .map(|c| match c & 0x7F {
+            b'\r' => b'\n',
+            c => c,
+        })
+        .collect()
+}
+
+pub fn text_from_host(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .map(|c| match c {
+            b'\n' => b'\r',
+            c => *c,
+        })
+        .collect()
+}
+
+// Names start with a letter, followed by up to 14 letters, digits or periods
+fn check_name(name: &str) -> Result<String, &'static str> {
+    let name = name.to_ascii_uppercase();
+    let valid = name.len() <= NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.');
+
+    match valid {
+        true => Ok(name),
+        false => Err("Names must be a letter followed by up to 14 letters, digits or periods."),
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|part| !part.is_empty()).collect()
+}
+
+impl ProDosDisk {
+    pub fn new(blocks: Vec<u8>) -> Result<Self, &'static str> {
+        let disk = ProDosDisk { blocks };
+        let header_idx = disk.block_idx(VOLUME_DIR_BLOCK)? + ENTRIES_START;
+
+        if disk.blocks[header_idx] >> 4 != storage_type::VOLUME_HEADER
+            || disk.blocks[header_idx + header::ENTRY_LENGTH] as usize != ENTRY_LENGTH
+        {
+            return Err("Disk does not contain a ProDOS filesystem.");
+        }
+
+        Ok(disk)
+    }
+
+    // Creates a blank volume (without a boot loader, so it can't be booted)
+    pub fn format(volume_name: &str, num_blocks: u16) -> Result<Self, &'static str> {
+        let volume_name = check_name(volume_name)?;
+        let bitmap_blocks = (num_blocks as usize).div_ceil(BLOCK_SIZE * 8) as u16;
+        if num_blocks < BITMAP_BLOCK + bitmap_blocks {
+            return Err("Volume is too small.");
+        }
+
+        let mut disk = ProDosDisk {
+            blocks: vec![0; num_blocks as usize * BLOCK_SIZE],
+        };
+
+        // The volume directory blocks are linked to each other in both directions
+        for i in 0..VOLUME_DIR_BLOCKS {
+            let block = VOLUME_DIR_BLOCK + i;
+            let idx = block as usize * BLOCK_SIZE;
+            let prev = match i > 0 {
+                true => block - 1,
+                false => 0,
+            };
+            let next = match i < VOLUME_DIR_BLOCKS - 1 {
+                true => block + 1,
+                false => 0,
+            };
+            put_bytes_2(prev, &mut disk.blocks, idx);
+            put_bytes_2(next, &mut disk.blocks, idx + 2);
+        }
+
+        let header_idx = VOLUME_DIR_BLOCK as usize * BLOCK_SIZE + ENTRIES_START;
+        disk.write_header(header_idx, storage_type::VOLUME_HEADER, &volume_name);
+        let volume_header = &mut disk.blocks[header_idx..header_idx + ENTRY_LENGTH];
+        put_bytes_2(BITMAP_BLOCK, volume_header, header::BITMAP_POINTER);
+        put_bytes_2(num_blocks, volume_header, header::TOTAL_BLOCKS);
+
+        // Everything up to the end of the bitmap is in use
+        for block in BITMAP_BLOCK + bitmap_blocks..num_blocks {
+            disk.set_free(block, true);
+        }
+
+        Ok(disk)
+    }
+
+    // Returns the blocks of the volume (in ProDOS order) including any changes made
+    pub fn into_blocks(self) -> Vec<u8> {
+        self.blocks
+    }
+
+    fn block_idx(&self, block: u16) -> Result<usize, &'static str> {
+        let idx = block as usize * BLOCK_SIZE;
+        match idx + BLOCK_SIZE <= self.blocks.len() {
+            true => Ok(idx),
+            false => Err("Disk contains an invalid block pointer."),
+        }
+    }
+
+    fn volume_header_idx(&self) -> usize {
+        VOLUME_DIR_BLOCK as usize * BLOCK_SIZE + ENTRIES_START
+    }
+
+    fn read_name(&self, idx: usize) -> String {
+        let len = (self.blocks[idx] & 0x0F) as usize;
+        String::from_utf8_lossy(&self.blocks[idx + 1..idx + 1 + len]).to_string()
+    }
+
+    pub fn volume_name(&self) -> String {
+        self.read_name(self.volume_header_idx())
+    }
+
+    fn total_blocks(&self) -> u16 {
+        get_bytes_2(
+            &self.blocks,
+            self.volume_header_idx() + header::TOTAL_BLOCKS,
+        )
+    }
+
+    fn bitmap_pos(&self, block: u16) -> (usize, u8) {
+        let bitmap = get_bytes_2(
+            &self.blocks,
+            self.volume_header_idx() + header::BITMAP_POINTER,
+        );
+        let byte = bitmap as usize * BLOCK_SIZE + block as usize / 8;
+        (byte, 0x80 >> (block % 8))
+    }
+
+    fn is_free(&self, block: u16) -> bool {
+        let (byte, mask) = self.bitmap_pos(block);
+        self.blocks.get(byte).is_some_and(|bits| bits & mask != 0)
+    }
+
+    fn set_free(&mut self, block: u16, free: bool) {
+        let (byte, mask) = self.bitmap_pos(block);
+        match free {
+            true => self.blocks[byte] |= mask,
+            false => self.blocks[byte] &= !mask,
+        }
+    }
+
+    fn free_blocks(&self) -> Vec<u16> {
+        (0..self.total_blocks())
+            .filter(|block| self.is_free(*block))
+            .collect()
+    }
+
+    // Allocates blocks and clears them out, failing if there aren't enough free
+    fn allocate(&mut self, count: usize) -> Result<Vec<u16>, &'static str> {
+        let free_blocks = self.free_blocks();
+        if free_blocks.len() < count {
+            return Err("Disk is full.");
+        }
+
+        let blocks = free_blocks[..count].to_vec();
+        for block in &blocks {
+            self.set_free(*block, false);
+            let idx = *block as usize * BLOCK_SIZE;
+            self.blocks[idx..idx + BLOCK_SIZE].fill(0);
+        }
+
+        Ok(blocks)
+    }
+
+    // Follows a chain of directory blocks, where each points to the next in bytes 2 and 3
+    fn dir_blocks(&self, key_block: u16) -> Result<Vec<u16>, &'static str> {
+        let mut blocks = Vec::new();
+        let mut block = key_block;
+
+        while block != 0 {
+            if blocks.len() >= MAX_CHAIN {
+                return Err("Disk contains a directory that never ends.");
+            }
+
+            let idx = self.block_idx(block)?;
+            blocks.push(block);
+            block = get_bytes_2(&self.blocks, idx + 2);
+        }
+
+        Ok(blocks)
+    }
+
+    // Byte offsets of every entry in a directory, used or not (skipping the header)
+    fn entry_idxs(&self, key_block: u16) -> Result<Vec<usize>, &'static str> {
+        Ok(self
+            .dir_blocks(key_block)?
+            .into_iter()
+            .flat_map(|block| {
+                (0..ENTRIES_PER_BLOCK)
+                    .map(move |i| block as usize * BLOCK_SIZE + ENTRIES_START + i * ENTRY_LENGTH)
+            })
+            .skip(1)
+            .collect())
+    }
+
+    fn read_entry(&self, entry_idx: usize) -> DirEntry {
+        let entry = &self.blocks[entry_idx..entry_idx + ENTRY_LENGTH];
+        let eof = u32::from_le_bytes([
+            entry[entry::EOF],
+            entry[entry::EOF + 1],
+            entry[entry::EOF + 2],
+            0,
+        ]);
+
+        DirEntry {
+            name: self.read_name(entry_idx),
+            file_type: entry[entry::FILE_TYPE],
+            aux_type: get_bytes_2(entry, entry::AUX_TYPE),
+            eof,
+            blocks_used: get_bytes_2(entry, entry::BLOCKS_USED),
+            locked: entry[entry::ACCESS] & access::WRITE == 0,
+            storage_type: entry[entry::STORAGE_TYPE] >> 4,
+            key_pointer: get_bytes_2(entry, entry::KEY_POINTER),
+            entry_idx,
+        }
+    }
+
+    fn read_dir(&self, key_block: u16) -> Result<Vec<DirEntry>, &'static str> {
+        Ok(self
+            .entry_idxs(key_block)?
+            .into_iter()
+            .filter(|idx| self.blocks[*idx] >> 4 != storage_type::DELETED)
+            .map(|idx| self.read_entry(idx))
+            .collect())
+    }
+
+    // Finds the key block of a directory, where an empty path is the volume directory
+    fn find_dir(&self, path: &[&str]) -> Result<u16, &'static str> {
+        let mut key_block = VOLUME_DIR_BLOCK;
+
+        for name in path {
+            let name = check_name(name)?;
+            let entry = self
+                .read_dir(key_block)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or("Directory not found.")?;
+
+            if !entry.is_dir() {
+                return Err("Path contains a file where a directory was expected.");
+            }
+            key_block = entry.key_pointer;
+        }
+
+        Ok(key_block)
+    }
+
+    // Splits a path into the key block of its directory and the name within it
+    fn resolve(&self, path: &str) -> Result<(u16, String), &'static str> {
+        let mut parts = split_path(path);
+
+        // Paths may start with the volume name, just like in ProDOS itself
+        if parts.len() > 1
+            && path.starts_with('/')
+            && parts[0].eq_ignore_ascii_case(&self.volume_name())
+        {
+            parts.remove(0);
+        }
+
+        let name = parts.pop().ok_or("Path is empty.")?;
+        Ok((self.find_dir(&parts)?, check_name(name)?))
+    }
+
+    fn find_entry(&self, path: &str) -> Result<DirEntry, &'static str> {
+        let (dir_block, name) = self.resolve(path)?;
+        self.read_dir(dir_block)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or("File not found.")
+    }
+
+    // Lists a directory, where an empty path (or "/") is the volume directory
+    pub fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, &'static str> {
+        let key_block = match split_path(path).is_empty() {
+            true => VOLUME_DIR_BLOCK,
+            false => {
+                let entry = self.find_entry(path)?;
+                if !entry.is_dir() {
+                    return Err("Path is not a directory.");
+                }
+                entry.key_pointer
+            }
+        };
+
+        self.read_dir(key_block)
+    }
+
+    fn index_pointers(&self, block: u16, count: usize) -> Result<Vec<u16>, &'static str> {
+        let idx = self.block_idx(block)?;
+
+        // Low bytes come first, followed by the high bytes
+        Ok((0..count)
+            .map(|i| u16::from_le_bytes([self.blocks[idx + i], self.blocks[idx + 256 + i]]))
+            .collect())
+    }
+
+    // Returns every block of a file, followed by the data blocks (0 meaning a sparse block)
+    fn file_blocks(&self, entry: &DirEntry) -> Result<(Vec<u16>, Vec<u16>), &'static str> {
+        let key = entry.key_pointer;
+        match entry.storage_type {
+            storage_type::SEEDLING => Ok((vec![key], vec![key])),
+            storage_type::SAPLING => {
+                let data = self.index_pointers(key, POINTERS_PER_INDEX)?;
+                Ok((vec![key], data))
+            }
+            storage_type::TREE => {
+                let mut index_blocks = vec![key];
+                let mut data = Vec::new();
+                for index in self.index_pointers(key, INDEXES_PER_MASTER)? {
+                    match index {
+                        0 => data.extend([0; POINTERS_PER_INDEX]),
+                        _ => {
+                            data.extend(self.index_pointers(index, POINTERS_PER_INDEX)?);
+                            index_blocks.push(index);
+                        }
+                    }
+                }
+                Ok((index_blocks, data))
+            }
+            _ => Err("File uses an unsupported storage type."),
+        }
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<(DirEntry, Vec<u8>), &'static str> {
+        let entry = self.find_entry(path)?;
+        if entry.is_dir() {
+            return Err("Path is a directory.");
+        }
+
+        let (_, data_blocks) = self.file_blocks(&entry)?;
+        let mut data = Vec::new();
+        for block in data_blocks {
+            if data.len() >= entry.eof as usize {
+                break;
+            }
+
+            match block {
+                0 => data.extend([0; BLOCK_SIZE]),
+                _ => {
+                    let idx = self.block_idx(block)?;
+                    data.extend_from_slice(&self.blocks[idx..idx + BLOCK_SIZE]);
+                }
+            }
+        }
+
+        data.truncate(entry.eof as usize);
+        Ok((entry, data))
+    }
+
+    fn change_file_count(&mut self, dir_block: u16, change: i16) {
+        let idx = dir_block as usize * BLOCK_SIZE + ENTRIES_START + header::FILE_COUNT;
+        let count = get_bytes_2(&self.blocks, idx).wrapping_add_signed(change);
+        put_bytes_2(count, &mut self.blocks, idx);
+    }
+
+    pub fn delete_file(&mut self, path: &str) -> Result<(), &'static str> {
+        let entry = self.find_entry(path)?;
+        if entry.locked {
+            return Err("File is locked.");
+        }
+        if entry.is_dir() {
+            return Err("Path is a directory.");
+        }
+
+        let (index_blocks, data_blocks) = self.file_blocks(&entry)?;
+        for block in index_blocks.into_iter().chain(data_blocks) {
+            if block != 0 {
+                self.set_free(block, true);
+            }
+        }
+
+        let dir_block = get_bytes_2(&self.blocks, entry.entry_idx + entry::HEADER_POINTER);
+        self.blocks[entry.entry_idx] = 0;
+        self.change_file_count(dir_block, -1);
+
+        Ok(())
+    }
+
+    // Only the volume directory has a fixed size, subdirectories grow as they fill up
+    fn has_free_entry(&self, dir_block: u16) -> Result<bool, &'static str> {
+        let free = self
+            .entry_idxs(dir_block)?
+            .into_iter()
+            .any(|idx| self.blocks[idx] >> 4 == storage_type::DELETED);
+        Ok(free || dir_block != VOLUME_DIR_BLOCK)
+    }
+
+    // Finds an unused entry in a directory, adding a block to subdirectories that are full
+    fn free_entry(&mut self, dir_block: u16) -> Result<usize, &'static str> {
+        let free = self
+            .entry_idxs(dir_block)?
+            .into_iter()
+            .find(|idx| self.blocks[*idx] >> 4 == storage_type::DELETED);
+        if let Some(idx) = free {
+            return Ok(idx);
+        }
+
+        if dir_block == VOLUME_DIR_BLOCK {
+            return Err("Volume directory is full.");
+        }
+
+        let last_block = *self.dir_blocks(dir_block)?.last().unwrap();
+        let new_block = self.allocate(1)?[0];
+        put_bytes_2(
+            new_block,
+            &mut self.blocks,
+            last_block as usize * BLOCK_SIZE + 2,
+        );
+        put_bytes_2(
+            last_block,
+            &mut self.blocks,
+            new_block as usize * BLOCK_SIZE,
+        );
+
+        // The subdirectory's entry in its parent keeps track of how big it is
+        let header_idx = dir_block as usize * BLOCK_SIZE + ENTRIES_START;
+        let parent_block = get_bytes_2(&self.blocks, header_idx + header::PARENT_POINTER);
+        let parent_num = self.blocks[header_idx + header::PARENT_ENTRY_NUMBER] as usize;
+        let parent_idx =
+            self.block_idx(parent_block)? + ENTRIES_START + (parent_num - 1) * ENTRY_LENGTH;
+        let parent = self.read_entry(parent_idx);
+        put_bytes_2(
+            parent.blocks_used + 1,
+            &mut self.blocks,
+            parent_idx + entry::BLOCKS_USED,
+        );
+        let eof = (parent.eof + BLOCK_SIZE as u32).to_le_bytes();
+        self.blocks[parent_idx + entry::EOF..parent_idx + entry::EOF + 3]
+            .copy_from_slice(&eof[..3]);
+
+        Ok(new_block as usize * BLOCK_SIZE + ENTRIES_START)
+    }
+
+    fn write_header(&mut self, idx: usize, storage_type: u8, name: &str) {
+        let header = &mut self.blocks[idx..idx + ENTRY_LENGTH];
+        header[0] = (storage_type << 4) | name.len() as u8;
+        header[1..1 + name.len()].copy_from_slice(name.as_bytes());
+        header[header::ACCESS] = access::UNLOCKED;
+        header[header::ENTRY_LENGTH] = ENTRY_LENGTH as u8;
+        header[header::ENTRIES_PER_BLOCK] = ENTRIES_PER_BLOCK as u8;
+    }
+
+    // Fills in a new entry of the directory starting at the given block
+    fn write_entry(&mut self, new_entry: &DirEntry, dir_block: u16) {
+        let idx = new_entry.entry_idx;
+        let name = &new_entry.name;
+
+        let entry = &mut self.blocks[idx..idx + ENTRY_LENGTH];
+        entry.fill(0);
+        entry[entry::STORAGE_TYPE] = (new_entry.storage_type << 4) | name.len() as u8;
+        entry[entry::NAME..entry::NAME + name.len()].copy_from_slice(name.as_bytes());
+        entry[entry::FILE_TYPE] = new_entry.file_type;
+        put_bytes_2(new_entry.key_pointer, entry, entry::KEY_POINTER);
+        put_bytes_2(new_entry.blocks_used, entry, entry::BLOCKS_USED);
+        entry[entry::EOF..entry::EOF + 3].copy_from_slice(&new_entry.eof.to_le_bytes()[..3]);
+        entry[entry::ACCESS] = match new_entry.locked {
+            true => access::LOCKED,
+            false => access::UNLOCKED,
+        };
+        put_bytes_2(new_entry.aux_type, entry, entry::AUX_TYPE);
+        put_bytes_2(dir_block, entry, entry::HEADER_POINTER);
+
+        self.change_file_count(dir_block, 1);
+    }
+
+    fn write_index(&mut self, block: u16, pointers: &[u16]) {
+        let idx = block as usize * BLOCK_SIZE;
+        for (i, pointer) in pointers.iter().enumerate() {
+            let [low, high] = pointer.to_le_bytes();
+            self.blocks[idx + i] = low;
+            self.blocks[idx + 256 + i] = high;
+        }
+    }
+
+    // Writes a new file, replacing any unlocked file with the same name
+    pub fn write_file(
+        &mut self,
+        path: &str,
+        file_type: u8,
+        aux_type: u16,
+        data: &[u8],
+        locked: bool,
+    ) -> Result<(), &'static str> {
+        let (dir_block, name) = self.resolve(path)?;
+        let existing = self
+            .read_dir(dir_block)?
+            .into_iter()
+            .find(|entry| entry.name == name);
+
+        // The blocks of a file being replaced can be used by its replacement
+        let reusable = match &existing {
+            Some(entry) if entry.is_dir() => {
+                return Err("A directory with that name already exists.")
+            }
+            Some(entry) if entry.locked => return Err("File is locked."),
+            Some(entry) => {
+                let (index_blocks, data_blocks) = self.file_blocks(entry)?;
+                index_blocks
+                    .into_iter()
+                    .chain(data_blocks)
+                    .filter(|block| *block != 0)
+                    .count()
+            }
+            None => 0,
+        };
+
+        // Seedlings hold one block of data, saplings up to 256, and trees the rest
+        let num_data = data.len().div_ceil(BLOCK_SIZE).max(1);
+        let num_indexes = num_data.div_ceil(POINTERS_PER_INDEX);
+        let (storage_type, num_index_blocks) = match num_data {
+            1 => (storage_type::SEEDLING, 0),
+            2..=POINTERS_PER_INDEX => (storage_type::SAPLING, 1),
+            _ if num_indexes <= INDEXES_PER_MASTER => (storage_type::TREE, 1 + num_indexes),
+            _ => return Err("File is too big."),
+        };
+
+        /* Make sure there is room for everything before changing anything. A new file may need
+        another directory block, while a replaced file leaves its entry free. */
+        let needed = num_data + num_index_blocks + existing.is_none() as usize;
+        if self.free_blocks().len() + reusable < needed {
+            return Err("Disk is full.");
+        }
+        if existing.is_none() && !self.has_free_entry(dir_block)? {
+            return Err("Volume directory is full.");
+        }
+        if existing.is_some() {
+            self.delete_file(path)?;
+        }
+
+        let entry_idx = self.free_entry(dir_block)?;
+        let index_blocks = self.allocate(num_index_blocks)?;
+        let data_blocks = self.allocate(num_data)?;
+
+        for (block, chunk) in data_blocks.iter().zip(data.chunks(BLOCK_SIZE)) {
+            let idx = *block as usize * BLOCK_SIZE;
+            self.blocks[idx..idx + chunk.len()].copy_from_slice(chunk);
+        }
+
+        let key_pointer = match storage_type {
+            storage_type::SEEDLING => data_blocks[0],
+            storage_type::SAPLING => {
+                self.write_index(index_blocks[0], &data_blocks);
+                index_blocks[0]
+            }
+            _ => {
+                self.write_index(index_blocks[0], &index_blocks[1..]);
+                for (index, pointers) in index_blocks[1..]
+                    .iter()
+                    .zip(data_blocks.chunks(POINTERS_PER_INDEX))
+                {
+                    self.write_index(*index, pointers);
+                }
+                index_blocks[0]
+            }
+        };
+
+        let new_entry = DirEntry {
+            name,
+            file_type,
+            aux_type,
+            eof: data.len() as u32,
+            blocks_used: (num_data + num_index_blocks) as u16,
+            locked,
+            storage_type,
+            key_pointer,
+            entry_idx,
+        };
+        self.write_entry(&new_entry, dir_block);
+
+        Ok(())
+    }
+
+    pub fn create_dir(&mut self, path: &str) -> Result<(), &'static str> {
+        let (dir_block, name) = self.resolve(path)?;
+        if self
+            .read_dir(dir_block)?
+            .iter()
+            .any(|entry| entry.name == name)
+        {
+            return Err("A file with that name already exists.");
+        }
+
+        if self.free_blocks().len() < 2 {
+            return Err("Disk is full.");
+        }
+        let entry_idx = self.free_entry(dir_block)?;
+        let key_block = self.allocate(1)?[0];
+
+        // The header points back to this directory's entry in its parent
+        let header_idx = key_block as usize * BLOCK_SIZE + ENTRIES_START;
+        self.write_header(header_idx, storage_type::SUBDIRECTORY_HEADER, &name);
+        let entry_offset = entry_idx % BLOCK_SIZE;
+        let subdir_header = &mut self.blocks[header_idx..header_idx + ENTRY_LENGTH];
+        subdir_header[header::RESERVED] = SUBDIRECTORY_MAGIC;
+        put_bytes_2(
+            (entry_idx / BLOCK_SIZE) as u16,
+            subdir_header,
+            header::PARENT_POINTER,
+        );
+        subdir_header[header::PARENT_ENTRY_NUMBER] =
+            ((entry_offset - ENTRIES_START) / ENTRY_LENGTH + 1) as u8;
+        subdir_header[header::PARENT_ENTRY_LENGTH] = ENTRY_LENGTH as u8;
+
+        let new_entry = DirEntry {
+            name,
+            file_type: file_type::DIR,
+            aux_type: 0,
+            eof: BLOCK_SIZE as u32,
+            blocks_used: 1,
+            locked: false,
+            storage_type: storage_type::SUBDIRECTORY,
+            key_pointer: key_block,
+            entry_idx,
+        };
+        self.write_entry(&new_entry, dir_block);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn write_read_delete() {
+        let mut disk = ProDosDisk::format("TEST", FLOPPY_BLOCKS).unwrap();
+        let data = test_data(5000, 0);
+
+        disk.write_file("PROGRAM", file_type::BIN, 0x2000, &data, false)
+            .unwrap();
+        disk.create_dir("GAMES").unwrap();
+        disk.write_file("GAMES/PONG", file_type::BIN, 0x0803, &data[..100], true)
+            .unwrap();
+
+        let (entry, read) = disk.read_file("PROGRAM").unwrap();
+        assert_eq!(entry.file_type, file_type::BIN);
+        assert_eq!(entry.aux_type, 0x2000);
+        assert_eq!(read, data);
+
+        let (entry, read) = disk.read_file("GAMES/PONG").unwrap();
+        assert_eq!(entry.aux_type, 0x0803);
+        assert!(entry.locked);
+        assert_eq!(read, &data[..100]);
+
+        disk.delete_file("PROGRAM").unwrap();
+        assert!(disk.read_file("PROGRAM").is_err());
+
+        // Everything survives the trip through a block image
+        let disk = ProDosDisk::new(disk.into_blocks()).unwrap();
+        assert_eq!(disk.volume_name(), "TEST");
+        let names: Vec<String> = disk
+            .list_dir("")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["GAMES"]);
+    }
+
+    #[test]
+    fn disk_full() {
+        let mut disk = ProDosDisk::format("TEST", FLOPPY_BLOCKS).unwrap();
+        disk.write_file("BIG1", file_type::BIN, 0x2000, &test_data(60000, 1), false)
+            .unwrap();
+        disk.write_file("BIG2", file_type::BIN, 0x2000, &test_data(60000, 2), false)
+            .unwrap();
+
+        assert!(disk
+            .write_file("BIG3", file_type::BIN, 0x2000, &test_data(60000, 3), false)
+            .is_err());
+        assert!(disk.read_file("BIG3").is_err());
+        assert_eq!(disk.list_dir("").unwrap().len(), 2);
+
+        // Replacing a file can reuse its blocks, and a replacement that doesn't fit keeps it
+        disk.write_file("BIG1", file_type::BIN, 0x2000, &test_data(60000, 4), false)
+            .unwrap();
+        assert_eq!(
+            disk.write_file("BIG2", file_type::BIN, 0x2000, &test_data(90000, 5), false)
+                .err(),
+            Some("Disk is full.")
+        );
+        assert_eq!(disk.read_file("BIG1").unwrap().1, test_data(60000, 4));
+        assert_eq!(disk.read_file("BIG2").unwrap().1, test_data(60000, 2));
+    }
+}
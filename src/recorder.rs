@@ -0,0 +1,78 @@
+/*
+Records a session into a directory, as a PNG image for every frame along with a WAV file of
+everything the speaker played. Frames come at the Apple 2's own rate of 1022727 / 17030 (about
+60.05) a second, so the video keeps in sync with the sound. Tools like ffmpeg can turn these into a
+video afterwards, e.g.
+ffmpeg -framerate 1022727/17030 -i frame_%06d.png -i audio.wav video.mp4
+*/
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::graphics;
+
+const AUDIO_FILE: &str = "audio.wav";
+
+pub struct Recorder {
+    dir: PathBuf,
+    frame_count: u32,
+    wav: WavWriter<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn start(dir: &Path, sample_rate: u32) -> Result<Self, &'static str> {
+        fs::create_dir_all(dir).map_err(|_| "Failed to create recording directory.")?;
+
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let wav = WavWriter::create(dir.join(AUDIO_FILE), spec)
+            .map_err(|_| "Failed to create recording audio file.")?;
+
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            frame_count: 0,
+            wav,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn add_frame(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Result<(), &'static str> {
+        self.frame_count += 1;
+        let file_path = self.dir.join(format!("frame_{:06}.png", self.frame_count));
+        graphics::write_png(&file_path, width, height, pixels)
+    }
+
+    // Samples range from -1.0 to 1.0
+    pub fn add_samples(&mut self, samples: &[f32]) -> Result<(), &'static str> {
+        for sample in samples {
+            self.wav
+                .write_sample((sample * i16::MAX as f32) as i16)
+                .map_err(|_| "Failed to write recording audio.")?;
+        }
+
+        Ok(())
+    }
+
+    // Finishes off the audio file, returning how many frames were recorded
+    pub fn stop(self) -> Result<u32, &'static str> {
+        self.wav
+            .finalize()
+            .map_err(|_| "Failed to finish recording audio.")?;
+        Ok(self.frame_count)
+    }
+}
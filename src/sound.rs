@@ -1,6 +1,12 @@
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 
-const SAMPLE_BUF_SZ: usize = 1024;
+use std::time::Duration;
+
+const SAMPLE_BUF_SZ: usize = 4096;
+
+// How much sound (about 2 frames) is kept queued up ahead of what is playing
+const MAX_QUEUED_SAMPLES: usize = 1536;
+const PLAYBACK_POLL_MS: u64 = 1;
 const SAMPLE_VOLUME: f32 = 0.5;
 pub const SAMPLE_RATE: u32 = 44100;
 
@@ -8,6 +14,14 @@ mod soft_switch {
     pub const SPEAKER: usize = 0xC030; // Whole page
 }
 
+// The level of the square wave for each polarity of the speaker
+pub fn sample_level(polarity: bool) -> f32 {
+    match polarity {
+        true => SAMPLE_VOLUME,
+        false => -SAMPLE_VOLUME,
+    }
+}
+
 pub struct SquareWave {
     buffer: [f32; SAMPLE_BUF_SZ],
     sample_idx: usize,
@@ -16,10 +30,19 @@ pub struct SquareWave {
 
 impl SquareWave {
     pub fn insert_sample(&mut self, sample: f32) {
+        // If the buffer is full (the emulator is running ahead of the audio), drop the sample
+        if self.queued_samples() == SAMPLE_BUF_SZ - 1 {
+            return;
+        }
+
         self.buffer[self.buf_idx] = sample;
         self.buf_idx += 1;
         self.buf_idx %= SAMPLE_BUF_SZ;
     }
+
+    fn queued_samples(&self) -> usize {
+        (self.buf_idx + SAMPLE_BUF_SZ - self.sample_idx) % SAMPLE_BUF_SZ
+    }
 }
 
 impl AudioCallback for SquareWave {
@@ -69,13 +92,18 @@ impl SoundHandler {
         }
     }
 
-    pub fn insert_samples(&mut self, samples: &Vec<bool>) {
+    pub fn insert_samples(&mut self, samples: &[f32]) {
         let mut lock = self.device.lock();
         for s in samples {
-            lock.insert_sample(match s {
-                true => SAMPLE_VOLUME,
-                false => -SAMPLE_VOLUME,
-            });
+            lock.insert_sample(*s);
+        }
+    }
+
+    /* The audio device plays samples at exactly the sample rate, so waiting for it to work
+    through the queue keeps the emulator running at real speed without audio drifting away. */
+    pub fn wait_for_playback(&mut self) {
+        while self.device.lock().queued_samples() > MAX_QUEUED_SAMPLES {
+            std::thread::sleep(Duration::from_millis(PLAYBACK_POLL_MS));
         }
     }
 
@@ -0,0 +1,217 @@
+/*
+Parses the header of a 2MG (2IMG) disk image, which is simply a DSK, PO, or NIB image wrapped
+with a 64-byte header describing its sector order, volume number, lock status and a comment.
+Images can also be wrapped up into a new 2MG image.
+
+2MG Reference: https://apple2.org.za/gswv/a2zine/Docs/DiskImage_2MG_Info.txt
+*/
+
+const HEADER_SIZE: usize = 64;
+const MAGIC: u32 = 0x474D4932; // "2IMG"
+const CREATOR: &[u8; 4] = b"RA2!";
+const VERSION: u16 = 1;
+const PRODOS_BLOCK_SIZE: usize = 512;
+
+mod flags {
+    pub const LOCKED: u32 = 1 << 31;
+    pub const VOLUME_VALID: u32 = 1 << 8;
+    pub const VOLUME_MASK: u32 = 0xFF;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Dos33,
+    ProDos,
+    Nib,
+}
+
+impl ImageFormat {
+    fn code(&self) -> u32 {
+        match self {
+            ImageFormat::Dos33 => 0,
+            ImageFormat::ProDos => 1,
+            ImageFormat::Nib => 2,
+        }
+    }
+}
+
+pub struct TwoMgImage<'a> {
+    pub format: ImageFormat,
+    pub volume: Option<u8>,
+    pub locked: bool,
+    pub comment: Option<String>,
+    pub data: &'a [u8],
+}
+
+// Data is stored in header in little-endian format
+fn get_bytes_4(buf: &[u8], start: usize) -> u32 {
+    u32::from_le_bytes(buf[start..start + 4].try_into().unwrap())
+}
+
+fn get_bytes_2(buf: &[u8], start: usize) -> u16 {
+    u16::from_le_bytes(buf[start..start + 2].try_into().unwrap())
+}
+
+fn get_chunk(file_buf: &[u8], offset: u32, length: u32) -> Result<&[u8], &'static str> {
+    let start = offset as usize;
+    let end = start + length as usize;
+
+    match end <= file_buf.len() {
+        true => Ok(&file_buf[start..end]),
+        false => Err("2MG image is truncated."),
+    }
+}
+
+pub fn parse(file_buf: &[u8]) -> Result<TwoMgImage<'_>, &'static str> {
+    if file_buf.len() < HEADER_SIZE || get_bytes_4(file_buf, 0) != MAGIC {
+        return Err("File is not a 2MG disk image.");
+    }
+
+    let header_size = get_bytes_2(file_buf, 8) as usize;
+    if header_size < HEADER_SIZE {
+        return Err("2MG image has an invalid header size.");
+    }
+
+    let format = match get_bytes_4(file_buf, 12) {
+        0 => ImageFormat::Dos33,
+        1 => ImageFormat::ProDos,
+        2 => ImageFormat::Nib,
+        _ => return Err("2MG image uses an unknown image format."),
+    };
+
+    let flags = get_bytes_4(file_buf, 16);
+    let volume = match flags & flags::VOLUME_VALID != 0 {
+        true => Some((flags & flags::VOLUME_MASK) as u8),
+        false => None,
+    };
+
+    let data = get_chunk(
+        file_buf,
+        get_bytes_4(file_buf, 24),
+        get_bytes_4(file_buf, 28),
+    )?;
+
+    // The comment is optional, and not necessarily null-terminated
+    let comment_len = get_bytes_4(file_buf, 36);
+    let comment = match comment_len {
+        0 => None,
+        _ => {
+            let bytes = get_chunk(file_buf, get_bytes_4(file_buf, 32), comment_len)?;
+            let comment = String::from_utf8_lossy(bytes);
+            Some(comment.trim_end_matches('\0').to_string())
+        }
+    };
+
+    Ok(TwoMgImage {
+        format,
+        volume,
+        locked: flags & flags::LOCKED != 0,
+        comment,
+        data,
+    })
+}
+
+fn put_u32(value: u32, buf: &mut [u8], start: usize) {
+    buf[start..start + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// Wraps the image data with a 2MG header, followed by the comment (if any)
+pub fn build(image: &TwoMgImage) -> Vec<u8> {
+    let mut file_buf = vec![0; HEADER_SIZE];
+
+    put_u32(MAGIC, &mut file_buf, 0);
+    file_buf[4..8].copy_from_slice(CREATOR);
+    file_buf[8..10].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    file_buf[10..12].copy_from_slice(&VERSION.to_le_bytes());
+    put_u32(image.format.code(), &mut file_buf, 12);
+
+    let mut flags = 0;
+    if image.locked {
+        flags |= flags::LOCKED;
+    }
+    if let Some(volume) = image.volume {
+        flags |= flags::VOLUME_VALID | volume as u32;
+    }
+    put_u32(flags, &mut file_buf, 16);
+
+    // Only ProDOS order images give their size in blocks
+    if image.format == ImageFormat::ProDos {
+        put_u32(
+            (image.data.len() / PRODOS_BLOCK_SIZE) as u32,
+            &mut file_buf,
+            20,
+        );
+    }
+
+    put_u32(HEADER_SIZE as u32, &mut file_buf, 24);
+    put_u32(image.data.len() as u32, &mut file_buf, 28);
+    file_buf.extend_from_slice(image.data);
+
+    if let Some(comment) = &image.comment {
+        put_u32(file_buf.len() as u32, &mut file_buf, 32);
+        put_u32(comment.len() as u32, &mut file_buf, 36);
+        file_buf.extend_from_slice(comment.as_bytes());
+    }
+
+    file_buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..143360).map(|i| i as u8).collect();
+
+        for format in [ImageFormat::Dos33, ImageFormat::ProDos, ImageFormat::Nib] {
+            let file_buf = build(&TwoMgImage {
+                format,
+                volume: Some(17),
+                locked: true,
+                comment: Some("A comment".to_string()),
+                data: &data,
+            });
+
+            let image = parse(&file_buf).unwrap();
+            assert!(image.format == format);
+            assert_eq!(image.volume, Some(17));
+            assert!(image.locked);
+            assert_eq!(image.comment.as_deref(), Some("A comment"));
+            assert_eq!(image.data, &data[..]);
+        }
+    }
+
+    #[test]
+    fn no_volume_or_lock() {
+        let data = vec![0xA5; 143360];
+        let file_buf = build(&TwoMgImage {
+            format: ImageFormat::ProDos,
+            volume: None,
+            locked: false,
+            comment: None,
+            data: &data,
+        });
+
+        let image = parse(&file_buf).unwrap();
+        assert_eq!(image.volume, None);
+        assert!(!image.locked);
+        assert_eq!(image.comment, None);
+        assert_eq!(get_bytes_4(&file_buf, 20), 280);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = vec![0; 143360];
+        let file_buf = build(&TwoMgImage {
+            format: ImageFormat::Dos33,
+            volume: None,
+            locked: false,
+            comment: None,
+            data: &data,
+        });
+
+        assert!(parse(&file_buf[..file_buf.len() - 1]).is_err());
+        assert!(parse(&file_buf[..HEADER_SIZE - 1]).is_err());
+    }
+}
@@ -1,24 +1,54 @@
 /*
 Wizard of Woz simply parses a raw WOZ2 image and returns a struct containing pertinent info.
+Other image types (DSK, PO, D13, NIB and 2MG) are converted into WOZ tracks first, and a directory
+on the host is turned into a ProDOS disk.
+It can also write the image back out as a WOZ2 image, and create blank disks.
 Reference: https://applesaucefdc.com/woz/reference2
 */
 
-use std::path::Path;
-use std::{fs::File, io::Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs::File, io::Write};
 
-use crate::dsk2woz;
+use crate::archive;
+use crate::dos33::Dos33Disk;
+use crate::dsk2woz::{self, SectorFormat};
+use crate::host_dir::HostDir;
+use crate::nib2woz;
+use crate::prodos::{self, ProDosDisk};
+use crate::twomg;
+use crate::woz2dsk;
+
+use flate2::Crc;
 
 const WOZ_IMG_SIZE: usize = 250000;
 
 const MAX_TRACKS: usize = 35;
+const BLOCK_SIZE: usize = 512;
 
 mod section_id {
     pub const WOZ2: u32 = 0x325A4F57;
     pub const INFO: u32 = 0x4F464E49;
     pub const TMAP: u32 = 0x50414D54;
     pub const TRKS: u32 = 0x534B5254;
+    pub const META: u32 = 0x4154454D;
 }
 
+// Sizes of the chunks as written by to_woz()
+const HEADER_SIZE: usize = 12;
+const INFO_SIZE: usize = 60;
+const TMAP_SIZE: usize = 160;
+
+// A quarter track that isn't mapped to any track only reads noise
+pub const NO_TRACK: u8 = 0xFF;
+const TRK_ENTRIES_SIZE: usize = 1280;
+const CREATOR: &str = "rust-apple2";
+
+// The length of a track on a disk that was never formatted (the same as most WOZ images use)
+const UNFORMATTED_TRACK_BITS: u32 = 51200;
+const BLANK_VOLUME_NAME: &str = "BLANK";
+
+#[derive(Clone)]
 pub struct WozTrack {
     pub bit_count: u32,
     pub data: Vec<u8>,
@@ -26,9 +56,47 @@ pub struct WozTrack {
 
 pub struct WozImage {
     pub write_protected: bool,
+    pub boot_sector_format: u8,
+    pub optimal_bit_timing: u8,
+    pub compatible_hardware: u16,
+    pub required_ram: u16,
     pub tracks: Vec<WozTrack>,
+    pub track_map: [u8; TMAP_SIZE],
+    pub meta: Vec<(String, String)>,
+    pub modified: bool,
+    file_path: PathBuf,
+    host_dir: Option<HostDir>,
+}
+
+// What a newly created disk holds
+#[derive(Clone, Copy)]
+pub enum BlankFormat {
+    Unformatted,
+    Dos33,
+    ProDos,
+}
+
+// Values of the INFO chunk fields we care about
+mod boot_format {
+    pub const UNKNOWN: u8 = 0;
+    pub const SECTOR_13: u8 = 2;
 }
 
+const DEFAULT_BIT_TIMING: u8 = 32; // In 125ns units, so 4us per bit
+const MAX_RAM_K: u16 = 64;
+
+const HARDWARE_NAMES: [&str; 9] = [
+    "Apple ][",
+    "Apple ][+",
+    "Apple //e",
+    "Apple //c",
+    "Apple //e Enhanced",
+    "Apple IIgs",
+    "Apple //c Plus",
+    "Apple ///",
+    "Apple /// Plus",
+];
+
 // Data is stored in image in little-endian format
 fn get_bytes_4(buf: &[u8], start: usize) -> u32 {
     u32::from_le_bytes(buf[start..start + 4].try_into().unwrap())
@@ -38,7 +106,37 @@ fn get_bytes_2(buf: &[u8], start: usize) -> u16 {
     u16::from_le_bytes(buf[start..start + 2].try_into().unwrap())
 }
 
+// A simple xorshift generator is plenty for filling unformatted tracks with noise
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn put_chunk(woz: &mut Vec<u8>, chunk_id: u32, data: &[u8]) {
+    woz.extend_from_slice(&chunk_id.to_le_bytes());
+    woz.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    woz.extend_from_slice(data);
+}
+
 impl WozImage {
+    fn empty(file_path: &Path) -> Self {
+        WozImage {
+            write_protected: false,
+            boot_sector_format: boot_format::UNKNOWN,
+            optimal_bit_timing: DEFAULT_BIT_TIMING,
+            compatible_hardware: 0,
+            required_ram: 0,
+            tracks: Vec::new(),
+            track_map: WozImage::standard_track_map(MAX_TRACKS),
+            meta: Vec::new(),
+            modified: false,
+            file_path: file_path.to_path_buf(),
+            host_dir: None,
+        }
+    }
+
     fn verify(file_buf: &[u8]) -> Result<(), &'static str> {
         let signature = get_bytes_4(file_buf, 0);
         let high_bits = file_buf[4];
@@ -51,50 +149,101 @@ impl WozImage {
         }
     }
 
-    fn parse_info(file_buf: &[u8], buf_pntr: usize) -> Result<bool, &'static str> {
+    fn parse_info(&mut self, file_buf: &[u8], buf_pntr: usize) -> Result<(), &'static str> {
         let version = file_buf[buf_pntr];
         let disk_type = file_buf[buf_pntr + 1];
-        let write_protected = file_buf[buf_pntr + 2];
-        let boot_sectors = file_buf[buf_pntr + 38];
-        let supported = get_bytes_2(file_buf, buf_pntr + 40);
-        let compatibile = supported == 0 || supported & 0x3 != 0;
-        // Lots of other things we can check in the future...
-
-        if version == 2 && disk_type == 1 && boot_sectors != 2 && compatibile {
-            Ok(matches!(write_protected, 1))
-        } else {
-            Err("This WOZ image is not supported.")
+
+        if version != 2 || disk_type != 1 {
+            return Err("This WOZ image is not supported.");
         }
+
+        self.write_protected = matches!(file_buf[buf_pntr + 2], 1);
+        self.boot_sector_format = file_buf[buf_pntr + 38];
+        self.compatible_hardware = get_bytes_2(file_buf, buf_pntr + 40);
+        self.required_ram = get_bytes_2(file_buf, buf_pntr + 42);
+
+        // A timing of 0 means the creator didn't specify, so assume the standard 4us
+        self.optimal_bit_timing = match file_buf[buf_pntr + 39] {
+            0 => DEFAULT_BIT_TIMING,
+            timing => timing,
+        };
+
+        Ok(())
     }
 
-    fn verify_track_map(file_buf: &[u8], buf_pntr: usize) -> Result<(), &'static str> {
-        for i in 0..160 {
-            let map = file_buf[buf_pntr + i];
+    // Disks that can only boot with the 13-sector (P5A) Disk II ROM
+    pub fn is_13_sector(&self) -> bool {
+        self.boot_sector_format == boot_format::SECTOR_13
+    }
 
-            if i >= 140 {
-                if map != 0xFF {
-                    return Err("WOZ images using more than 35 tracks is not supported.");
-                }
+    // Returns the names of all the machines this disk claims to run on
+    pub fn compatible_hardware_names(&self) -> Vec<&'static str> {
+        HARDWARE_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.compatible_hardware & (1 << i) != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
 
-                continue;
-            }
+    // A compatible hardware field of 0 means unknown, so assume it will work
+    pub fn is_compatible(&self) -> bool {
+        self.compatible_hardware == 0 || self.compatible_hardware & 0x3 != 0
+    }
 
-            if i % 4 == 0 && map != (i / 4) as u8 {
-                println!("Map: {map}, {}", i / 4);
-                return Err("This WOZ image uses unsupported track mapping.");
-            } else if i % 2 == 0 && i % 4 != 0 && map != 0xFF {
-                return Err("This WOZ image utilizes odd tracks which is not supported.");
-            }
+    pub fn print_info(&self) {
+        if self.compatible_hardware != 0 {
+            println!(
+                "Disk compatible with: {}",
+                self.compatible_hardware_names().join(", ")
+            );
+        }
+        if self.required_ram != 0 {
+            println!("Disk requires {}K of RAM.", self.required_ram);
         }
 
-        Ok(())
+        if !self.is_compatible() {
+            println!("Warning: This disk does not list the Apple ][ or ][+ as compatible.");
+        }
+        if self.required_ram > MAX_RAM_K {
+            println!("Warning: This disk requires more RAM than the emulated {MAX_RAM_K}K.");
+        }
+    }
+
+    // The quarter tracks on either side of a track read the same data
+    fn standard_track_map(num_tracks: usize) -> [u8; TMAP_SIZE] {
+        let mut track_map = [NO_TRACK; TMAP_SIZE];
+
+        for i in 0..num_tracks {
+            let idx = i * 4;
+            track_map[idx.saturating_sub(1)..idx + 2].fill(i as u8);
+        }
+
+        track_map
     }
 
+    fn verify_track_map(&self) -> Result<(), &'static str> {
+        let num_tracks = self.tracks.len();
+        match self
+            .track_map
+            .iter()
+            .all(|&map| map == NO_TRACK || (map as usize) < num_tracks)
+        {
+            true => Ok(()),
+            false => Err("This WOZ image maps a quarter track to a track it doesn't have."),
+        }
+    }
+
+    // Unused TRK entries are all zeros and only come after the tracks in use
     fn parse_tracks(file_buf: &[u8], buf_pntr: usize, tracks: &mut Vec<WozTrack>) {
-        for i in 0..MAX_TRACKS {
+        for i in 0..TMAP_SIZE {
             let offset = buf_pntr + (i * 8);
             let block_addr = get_bytes_2(file_buf, offset) as usize * 512;
             let bit_count = get_bytes_4(file_buf, offset + 4);
+            if block_addr == 0 {
+                break;
+            }
+
             let byte_count = (bit_count as f32 / 8.0).ceil() as usize;
             let mut data: Vec<u8> = Vec::new();
 
@@ -106,43 +255,61 @@ impl WozImage {
         }
     }
 
-    pub fn new(file_path: &Path) -> Result<Self, &'static str> {
-        let mut file_buf = [0; WOZ_IMG_SIZE];
-        let ext = file_path.extension().unwrap().to_str().unwrap();
+    // The META chunk is UTF-8 text with a tab separated key and value on each line
+    fn parse_meta(&mut self, file_buf: &[u8], buf_pntr: usize, chunk_size: usize) {
+        let end = (buf_pntr + chunk_size).min(file_buf.len());
+        let text = String::from_utf8_lossy(&file_buf[buf_pntr..end]);
 
-        if ext == "woz" {
-            let mut image = File::open(file_path).expect("Failed to open WOZ image!");
-            image
-                .read_exact(&mut file_buf)
-                .expect("Failed to read WOZ image data!");
-        } else if ext == "dsk" {
-            dsk2woz::convert(file_path, &mut file_buf, false);
-        } else if ext == "po" {
-            dsk2woz::convert(file_path, &mut file_buf, true);
-        } else {
-            return Err("Unsupported disk image type.");
+        self.meta = text
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+    }
+
+    pub fn get_meta(&self, key: &str) -> Option<&str> {
+        self.meta
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        if key.is_empty() || key.contains(['\t', '\n']) || value.contains(['\t', '\n']) {
+            return Err("META keys and values may not contain tabs or newlines.");
+        }
+
+        match self.meta.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.meta.push((key.to_string(), value.to_string())),
         }
 
-        WozImage::verify(&file_buf)?;
+        Ok(())
+    }
+
+    fn parse_woz(&mut self, file_buf: &[u8]) -> Result<(), &'static str> {
+        WozImage::verify(file_buf)?;
 
-        let mut write_protected = false;
-        let mut tracks = Vec::new();
         let mut buf_pntr: usize = 12;
 
-        loop {
-            let chunk_id = get_bytes_4(&file_buf, buf_pntr);
-            let chunk_size = get_bytes_4(&file_buf, buf_pntr + 4);
+        while buf_pntr + 8 <= file_buf.len() {
+            let chunk_id = get_bytes_4(file_buf, buf_pntr);
+            let chunk_size = get_bytes_4(file_buf, buf_pntr + 4);
             buf_pntr += 8;
 
             match chunk_id {
                 section_id::INFO => {
-                    write_protected = WozImage::parse_info(&file_buf, buf_pntr)?;
+                    self.parse_info(file_buf, buf_pntr)?;
                 }
                 section_id::TMAP => {
-                    WozImage::verify_track_map(&file_buf, buf_pntr)?;
+                    self.track_map
+                        .copy_from_slice(&file_buf[buf_pntr..buf_pntr + TMAP_SIZE]);
                 }
                 section_id::TRKS => {
-                    WozImage::parse_tracks(&file_buf, buf_pntr, &mut tracks);
+                    WozImage::parse_tracks(file_buf, buf_pntr, &mut self.tracks);
+                }
+                section_id::META => {
+                    self.parse_meta(file_buf, buf_pntr, chunk_size as usize);
                 }
                 _ => {
                     break; // Unknown chunk, so stop
@@ -152,9 +319,358 @@ impl WozImage {
             buf_pntr += chunk_size as usize;
         }
 
-        Ok(WozImage {
-            write_protected,
-            tracks,
-        })
+        self.verify_track_map()
+    }
+
+    fn parse_dsk(
+        &mut self,
+        dsk_buf: &[u8],
+        format: SectorFormat,
+        volume: u8,
+    ) -> Result<(), &'static str> {
+        /* Only 140K floppies can be mounted. Anything bigger (like an 800K or hard disk image)
+        would lose everything past the first 140K when the image is saved. */
+        if dsk_buf.len() != format.image_size() {
+            return Err("Disk image is not the size of a 140K floppy disk.");
+        }
+
+        let mut woz_buf = vec![0; WOZ_IMG_SIZE];
+        dsk2woz::convert(dsk_buf, &mut woz_buf, format, volume);
+        self.parse_woz(&woz_buf)
+    }
+
+    fn parse_nib(&mut self, nib_buf: &[u8]) -> Result<(), &'static str> {
+        if nib_buf.len() < nib2woz::NIB_IMG_SIZE {
+            return Err("NIB image is too small.");
+        }
+
+        // NIB images are converted straight into tracks since there is no other info to parse
+        self.tracks = nib2woz::convert(nib_buf);
+        Ok(())
+    }
+
+    fn parse_2mg(&mut self, file_buf: &[u8]) -> Result<(), &'static str> {
+        let two_mg = twomg::parse(file_buf)?;
+
+        if let Some(comment) = &two_mg.comment {
+            println!("Disk comment: {comment}");
+        }
+
+        let volume = two_mg.volume.unwrap_or(dsk2woz::DEFAULT_VOLUME);
+        match two_mg.format {
+            twomg::ImageFormat::Dos33 => {
+                self.parse_dsk(two_mg.data, SectorFormat::Dos33, volume)?
+            }
+            twomg::ImageFormat::ProDos => {
+                self.parse_dsk(two_mg.data, SectorFormat::ProDos, volume)?
+            }
+            twomg::ImageFormat::Nib => self.parse_nib(two_mg.data)?,
+        }
+
+        // The locked flag takes the place of the write protect tab
+        self.write_protected = two_mg.locked;
+        Ok(())
+    }
+
+    // The archive index selects which image to use when the file is a zip of several images
+    pub fn new(file_path: &Path, archive_index: usize) -> Result<Self, &'static str> {
+        if file_path.is_dir() {
+            let (host_dir, blocks) = HostDir::mount(file_path)?;
+            let mut woz_image =
+                WozImage::from_sectors(&blocks, SectorFormat::ProDos, dsk2woz::DEFAULT_VOLUME)?;
+            woz_image.host_dir = Some(host_dir);
+            return Ok(woz_image);
+        }
+
+        let (image_name, file_buf) = archive::read_image(file_path, archive_index)?;
+        let ext = match Path::new(&image_name).extension() {
+            Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+            None => String::new(),
+        };
+
+        let mut woz_image = WozImage::empty(file_path);
+
+        match ext.as_str() {
+            "woz" => woz_image.parse_woz(&file_buf)?,
+            "dsk" | "do" => {
+                woz_image.parse_dsk(&file_buf, SectorFormat::Dos33, dsk2woz::DEFAULT_VOLUME)?
+            }
+            "po" => {
+                woz_image.parse_dsk(&file_buf, SectorFormat::ProDos, dsk2woz::DEFAULT_VOLUME)?
+            }
+            "d13" => {
+                woz_image.parse_dsk(&file_buf, SectorFormat::Dos32, dsk2woz::DEFAULT_VOLUME)?
+            }
+            "nib" => woz_image.parse_nib(&file_buf)?,
+            "2mg" | "2img" => woz_image.parse_2mg(&file_buf)?,
+            _ => return Err("Unsupported disk image type."),
+        }
+
+        Ok(woz_image)
+    }
+
+    // Creates an image from the raw sector data of a DSK, PO or D13 image
+    pub fn from_sectors(
+        dsk: &[u8],
+        format: SectorFormat,
+        volume: u8,
+    ) -> Result<Self, &'static str> {
+        let mut woz_image = WozImage::empty(Path::new(""));
+        woz_image.parse_dsk(dsk, format, volume)?;
+        Ok(woz_image)
+    }
+
+    /* Creates a new disk and writes it out to the given file. Unformatted disks are filled with
+    random bits, just like the noise the drive picks up from a disk that was never written to. */
+    pub fn blank(file_path: &Path, format: BlankFormat) -> Result<Self, &'static str> {
+        let ext = match file_path.extension() {
+            Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+            None => String::new(),
+        };
+        if ext == "d13" {
+            return Err("Blank 13-sector disks can't be created.");
+        }
+
+        let mut woz_image = match format {
+            BlankFormat::Unformatted => {
+                if ext != "woz" && ext != "nib" {
+                    return Err("Unformatted disks can only be WOZ or NIB images.");
+                }
+
+                let mut woz_image = WozImage::empty(file_path);
+                let mut state = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(1, |time| time.as_nanos() as u64 | 1);
+                for _ in 0..MAX_TRACKS {
+                    let data = (0..UNFORMATTED_TRACK_BITS / 8)
+                        .map(|_| next_random(&mut state) as u8)
+                        .collect();
+                    woz_image.tracks.push(WozTrack {
+                        bit_count: UNFORMATTED_TRACK_BITS,
+                        data,
+                    });
+                }
+
+                woz_image
+            }
+            BlankFormat::Dos33 => {
+                let disk = Dos33Disk::format(dsk2woz::DEFAULT_VOLUME);
+                WozImage::from_sectors(
+                    &disk.into_sectors(),
+                    SectorFormat::Dos33,
+                    dsk2woz::DEFAULT_VOLUME,
+                )?
+            }
+            BlankFormat::ProDos => {
+                let disk = ProDosDisk::format(BLANK_VOLUME_NAME, prodos::FLOPPY_BLOCKS)?;
+                WozImage::from_sectors(
+                    &disk.into_blocks(),
+                    SectorFormat::ProDos,
+                    dsk2woz::DEFAULT_VOLUME,
+                )?
+            }
+        };
+
+        woz_image.file_path = file_path.to_path_buf();
+        woz_image.save()?;
+        Ok(woz_image)
+    }
+
+    /* The track found at each whole track position, in the order sector and NIB images store
+    them. The track map decides which track that is, and may leave a position without one. */
+    pub fn whole_tracks(&self) -> Result<Vec<WozTrack>, &'static str> {
+        (0..MAX_TRACKS)
+            .map(|track| match self.track_map[track * 4] {
+                NO_TRACK => Err("Disk image has no data on one of its whole tracks."),
+                track_idx => Ok(self.tracks[track_idx as usize].clone()),
+            })
+            .collect()
+    }
+
+    // Decodes every sector on the disk, returning them in the order of the given image type
+    pub fn to_sectors(&self, format: SectorFormat) -> Result<Vec<u8>, &'static str> {
+        woz2dsk::convert(&self.whole_tracks()?, format)
+    }
+
+    pub fn to_nib(&self) -> Result<Vec<u8>, &'static str> {
+        Ok(nib2woz::to_nib(&self.whole_tracks()?))
+    }
+
+    // The sector format the disk was (most likely) written with
+    pub fn sector_format(&self) -> SectorFormat {
+        match self.is_13_sector() {
+            true => SectorFormat::Dos32,
+            false => SectorFormat::Dos33,
+        }
+    }
+
+    // The volume number in the address fields of the disk, if any can be read
+    pub fn volume(&self) -> Option<u8> {
+        let track = self.tracks.get(self.track_map[0] as usize)?;
+        woz2dsk::read_volume(track, self.sector_format())
+    }
+
+    // Re-encodes every track with a new volume number in its address fields
+    pub fn set_volume(&mut self, volume: u8) -> Result<(), &'static str> {
+        let format = self.sector_format();
+        let dsk = self.to_sectors(format)?;
+        self.replace_tracks(WozImage::from_sectors(&dsk, format, volume)?);
+        Ok(())
+    }
+
+    // Takes over the tracks of another image, along with which quarter tracks they are on
+    pub fn replace_tracks(&mut self, woz_image: WozImage) {
+        self.tracks = woz_image.tracks;
+        self.track_map = woz_image.track_map;
+    }
+
+    fn build_info(&self, largest_track: u16) -> Vec<u8> {
+        let mut info = vec![0; INFO_SIZE];
+
+        info[0] = 2; // Version
+        info[1] = 1; // 5.25" disk
+        info[2] = self.write_protected as u8;
+        info[5..37].fill(b' ');
+        info[5..5 + CREATOR.len()].copy_from_slice(CREATOR.as_bytes());
+        info[37] = 1; // Disk sides
+        info[38] = self.boot_sector_format;
+        info[39] = self.optimal_bit_timing;
+        info[40..42].copy_from_slice(&self.compatible_hardware.to_le_bytes());
+        info[42..44].copy_from_slice(&self.required_ram.to_le_bytes());
+        info[44..46].copy_from_slice(&largest_track.to_le_bytes());
+
+        info
+    }
+
+    // Returns the image as a complete WOZ2 file
+    pub fn to_woz(&self) -> Vec<u8> {
+        // Track data starts on the first block after the TRK entries, with each track padded out
+        let mut trk_entries = vec![0; TRK_ENTRIES_SIZE];
+        let mut track_data = Vec::new();
+        let first_block = (HEADER_SIZE + 8 + INFO_SIZE + 8 + TMAP_SIZE + 8 + TRK_ENTRIES_SIZE)
+            .div_ceil(BLOCK_SIZE);
+        let mut largest_track = 0;
+
+        for (i, track) in self.tracks.iter().enumerate() {
+            let num_blocks = track.data.len().div_ceil(BLOCK_SIZE);
+            let start_block = first_block + track_data.len() / BLOCK_SIZE;
+            let entry = &mut trk_entries[i * 8..i * 8 + 8];
+            entry[0..2].copy_from_slice(&(start_block as u16).to_le_bytes());
+            entry[2..4].copy_from_slice(&(num_blocks as u16).to_le_bytes());
+            entry[4..8].copy_from_slice(&track.bit_count.to_le_bytes());
+
+            track_data.extend_from_slice(&track.data);
+            track_data.resize(track_data.len().next_multiple_of(BLOCK_SIZE), 0);
+            largest_track = largest_track.max(num_blocks as u16);
+        }
+
+        let mut woz = Vec::new();
+        woz.extend_from_slice(&section_id::WOZ2.to_le_bytes());
+        woz.extend_from_slice(&[0xFF, 0x0A, 0x0D, 0x0A]);
+        woz.extend_from_slice(&[0; 4]); // CRC gets filled in at the end
+
+        put_chunk(&mut woz, section_id::INFO, &self.build_info(largest_track));
+        put_chunk(&mut woz, section_id::TMAP, &self.track_map);
+
+        // The TRKS chunk includes the track data, which starts on a block boundary
+        trk_entries.resize(first_block * BLOCK_SIZE - (woz.len() + 8), 0);
+        trk_entries.extend_from_slice(&track_data);
+        put_chunk(&mut woz, section_id::TRKS, &trk_entries);
+
+        if !self.meta.is_empty() {
+            let meta: String = self
+                .meta
+                .iter()
+                .map(|(key, value)| format!("{key}\t{value}\n"))
+                .collect();
+            put_chunk(&mut woz, section_id::META, meta.as_bytes());
+        }
+
+        let mut crc = Crc::new();
+        crc.update(&woz[HEADER_SIZE..]);
+        woz[8..12].copy_from_slice(&crc.sum().to_le_bytes());
+
+        woz
+    }
+
+    // Rebuilds the volume of a mounted host directory when its files were changed on the host
+    pub fn refresh_host_dir(&mut self) -> Result<bool, &'static str> {
+        let root = match self.host_dir.as_mut() {
+            Some(host_dir) => match host_dir.changed_on_host() {
+                true => host_dir.root().to_path_buf(),
+                false => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+
+        let (host_dir, blocks) = HostDir::mount(&root)?;
+        let woz_image =
+            WozImage::from_sectors(&blocks, SectorFormat::ProDos, dsk2woz::DEFAULT_VOLUME)?;
+        self.replace_tracks(woz_image);
+        self.host_dir = Some(host_dir);
+        Ok(true)
+    }
+
+    /* Writes any changes made to the disk back to the image file it was loaded from. Sector images
+    can only be written while every sector on the disk can still be read. */
+    pub fn save(&mut self) -> Result<(), &'static str> {
+        if let Some(mut host_dir) = self.host_dir.take() {
+            let result = self
+                .to_sectors(SectorFormat::ProDos)
+                .and_then(|blocks| host_dir.write_back(blocks));
+            self.host_dir = Some(host_dir);
+            result?;
+            self.modified = false;
+            return Ok(());
+        }
+
+        let ext = match self.file_path.extension() {
+            Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+            None => String::new(),
+        };
+        let file_buf = match ext.as_str() {
+            "woz" => self.to_woz(),
+            "dsk" | "do" => self.to_sectors(SectorFormat::Dos33)?,
+            "po" => self.to_sectors(SectorFormat::ProDos)?,
+            "d13" => self.to_sectors(SectorFormat::Dos32)?,
+            "nib" => self.to_nib()?,
+            _ => return Err("Writing back to this disk image type is not supported."),
+        };
+
+        let mut image =
+            File::create(&self.file_path).map_err(|_| "Failed to create disk image.")?;
+        image
+            .write_all(&file_buf)
+            .map_err(|_| "Failed to write disk image data.")?;
+
+        self.modified = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_found_through_track_map() {
+        let dsk: Vec<u8> = (0..SectorFormat::Dos33.image_size())
+            .map(|i| (i * 5 + i / 256) as u8)
+            .collect();
+        let mut woz_image =
+            WozImage::from_sectors(&dsk, SectorFormat::Dos33, dsk2woz::DEFAULT_VOLUME).unwrap();
+
+        // Store the first two tracks the other way around, and move track 2 onto a half track
+        woz_image.tracks.swap(0, 1);
+        woz_image.track_map[0..2].fill(1);
+        woz_image.track_map[3..6].fill(0);
+        woz_image.track_map[7..10].fill(NO_TRACK);
+        woz_image.track_map[9..12].fill(2);
+
+        assert!(woz_image.to_sectors(SectorFormat::Dos33).is_err());
+        assert!(woz_image.to_nib().is_err());
+
+        woz_image.track_map[7..10].fill(2);
+        assert_eq!(woz_image.to_sectors(SectorFormat::Dos33).unwrap(), dsk);
     }
 }
@@ -0,0 +1,272 @@
+/*
+Converts WOZ tracks back into a DSK, PO or D13 image by finding and decoding every sector on each
+track. This is simply the reverse of dsk2woz, so it only works for disks that use the standard
+DOS 3.2, DOS 3.3 or ProDOS track format (copy protected disks will most likely fail).
+
+Reference: Beneath Apple DOS, chapter 3
+*/
+
+use crate::dsk2woz::{self, SectorFormat};
+use crate::nib2woz;
+use crate::wizard_of_woz::WozTrack;
+
+const NUM_TRACKS: usize = 35;
+const BYTES_PER_SECTOR: usize = 256;
+const GCR_BYTES_PER_SECTOR: usize = 343;
+const GCR_BYTES_PER_SECTOR_5_3: usize = 411;
+const CHUNK_SIZE_5_3: usize = 51;
+
+const ADDRESS_FIELD_SIZE: usize = 11;
+
+// How many disk bytes after the address field we look for the data prologue before giving up
+const MAX_DATA_GAP: usize = 64;
+
+const INVALID: u8 = 0xFF;
+
+// Builds a table that maps disk bytes back to the values they were translated from
+fn reverse_map(map: &[u8]) -> [u8; 256] {
+    let mut reverse = [INVALID; 256];
+    for (i, byte) in map.iter().enumerate() {
+        reverse[*byte as usize] = i as u8;
+    }
+
+    reverse
+}
+
+fn decode_4_4(odd: u8, even: u8) -> u8 {
+    ((odd << 1) | 1) & even
+}
+
+/* Translates disk bytes back into values and undoes the running XOR. The final disk byte is the
+checksum, which gives 0 when XORed with the last value if the sector was read correctly. */
+fn decode_gcr(gcr_bytes: &[u8], map: &[u8]) -> Option<Vec<u8>> {
+    let reverse = reverse_map(map);
+    let mut vals = Vec::with_capacity(gcr_bytes.len());
+    let mut prev = 0;
+
+    for byte in gcr_bytes {
+        let val = reverse[*byte as usize];
+        if val == INVALID {
+            return None;
+        }
+
+        prev ^= val;
+        vals.push(prev);
+    }
+
+    match vals.pop() {
+        Some(0) => Some(vals),
+        _ => None,
+    }
+}
+
+fn decode_6_2(gcr_bytes: &[u8]) -> Option<[u8; BYTES_PER_SECTOR]> {
+    let vals = decode_gcr(gcr_bytes, &dsk2woz::TRANSLATE_6_2)?;
+    let mut sector = [0; BYTES_PER_SECTOR];
+
+    // The first 86 values hold the lowest 2 bits (reversed) of three data bytes each
+    let bit_reverse = [0, 2, 1, 3];
+    for (i, byte) in sector.iter_mut().enumerate() {
+        let low_bits = (vals[i % 86] >> ((i / 86) * 2)) & 3;
+        *byte = (vals[86 + i] << 2) | bit_reverse[low_bits as usize];
+    }
+
+    Some(sector)
+}
+
+fn decode_5_3(gcr_bytes: &[u8]) -> Option<[u8; BYTES_PER_SECTOR]> {
+    let vals = decode_gcr(gcr_bytes, &dsk2woz::TRANSLATE_5_3)?;
+    let mut sector = [0; BYTES_PER_SECTOR];
+
+    // The threes were written first in reverse order, followed by the top 5 bits of every byte
+    let num_threes = CHUNK_SIZE_5_3 * 3 + 1;
+    let threes: Vec<u8> = vals[..num_threes].iter().rev().copied().collect();
+    let top = &vals[num_threes..];
+
+    // Put each group of 5 bytes back together from their top bits and the three "threes" bytes
+    for (i, group) in sector[..CHUNK_SIZE_5_3 * 5].chunks_mut(5).enumerate() {
+        let chunk = CHUNK_SIZE_5_3 - 1 - i;
+        let three = [
+            threes[chunk],
+            threes[chunk + CHUNK_SIZE_5_3],
+            threes[chunk + CHUNK_SIZE_5_3 * 2],
+        ];
+
+        for j in 0..3 {
+            group[j] = (top[chunk + CHUNK_SIZE_5_3 * j] << 3) | (three[j] >> 2);
+        }
+        group[3] = (top[chunk + CHUNK_SIZE_5_3 * 3] << 3)
+            | ((three[0] & 2) << 1)
+            | (three[1] & 2)
+            | ((three[2] & 2) >> 1);
+        group[4] = (top[chunk + CHUNK_SIZE_5_3 * 4] << 3)
+            | ((three[0] & 1) << 2)
+            | ((three[1] & 1) << 1)
+            | (three[2] & 1);
+    }
+
+    sector[255] = (top[255] << 3) | (threes[CHUNK_SIZE_5_3 * 3] & 7);
+
+    Some(sector)
+}
+
+fn find_prologue(nibbles: &[u8], prologue: [u8; 3]) -> Option<usize> {
+    nibbles.windows(3).position(|window| window == prologue)
+}
+
+fn address_prologue(format: SectorFormat) -> [u8; 3] {
+    match format {
+        SectorFormat::Dos32 => [0xD5, 0xAA, 0xB5],
+        _ => [0xD5, 0xAA, 0x96],
+    }
+}
+
+// Reads a little over a full revolution of a track, as disk bytes
+fn track_nibbles(track: &WozTrack) -> Vec<u8> {
+    let mut nibbles = vec![0; track.bit_count as usize / 4];
+    nib2woz::read_track(track, &mut nibbles);
+    nibbles
+}
+
+// Finds the volume number of the disk in the first good address field of a track
+pub fn read_volume(track: &WozTrack, format: SectorFormat) -> Option<u8> {
+    let nibbles = track_nibbles(track);
+    let prologue = address_prologue(format);
+
+    nibbles
+        .windows(ADDRESS_FIELD_SIZE)
+        .filter(|field| field[..3] == prologue)
+        .map(|field| {
+            let field = &field[ADDRESS_FIELD_SIZE - 8..];
+            let volume = decode_4_4(field[0], field[1]);
+            let track = decode_4_4(field[2], field[3]);
+            let sector = decode_4_4(field[4], field[5]);
+            let checksum = decode_4_4(field[6], field[7]);
+            (volume, volume ^ track ^ sector == checksum)
+        })
+        .find(|(_, valid)| *valid)
+        .map(|(volume, _)| volume)
+}
+
+fn convert_track(
+    track: &WozTrack,
+    dsk: &mut [u8],
+    track_num: u8,
+    format: SectorFormat,
+) -> Result<(), &'static str> {
+    let num_sectors = format.num_sectors() as usize;
+    let address_prologue = address_prologue(format);
+    let gcr_size = match format {
+        SectorFormat::Dos32 => GCR_BYTES_PER_SECTOR_5_3,
+        _ => GCR_BYTES_PER_SECTOR,
+    };
+
+    /* Read a little over a full revolution, since the first sector we come across may have been
+    cut off where the track wraps around. Sync bytes are 10 bits, so this is plenty. */
+    let nibbles = track_nibbles(track);
+
+    let mut found = vec![false; num_sectors];
+    let mut idx = 0;
+
+    while let Some(start) = find_prologue(&nibbles[idx..], address_prologue) {
+        idx += start + ADDRESS_FIELD_SIZE;
+        if idx > nibbles.len() {
+            break;
+        }
+
+        // Volume, track, sector, and checksum
+        let field = &nibbles[idx - 8..idx];
+        let volume = decode_4_4(field[0], field[1]);
+        let track = decode_4_4(field[2], field[3]);
+        let sector = decode_4_4(field[4], field[5]) as usize;
+        let checksum = decode_4_4(field[6], field[7]);
+
+        if volume ^ track ^ sector as u8 != checksum
+            || track != track_num
+            || sector >= num_sectors
+            || found[sector]
+        {
+            continue;
+        }
+
+        // The data field should follow closely behind its address field
+        let search_end = (idx + MAX_DATA_GAP).min(nibbles.len());
+        let data_start = match find_prologue(&nibbles[idx..search_end], [0xD5, 0xAA, 0xAD]) {
+            Some(start) => idx + start + 3,
+            None => continue,
+        };
+        if data_start + gcr_size > nibbles.len() {
+            continue;
+        }
+
+        let gcr_bytes = &nibbles[data_start..data_start + gcr_size];
+        let sector_data = match format {
+            SectorFormat::Dos32 => decode_5_3(gcr_bytes),
+            _ => decode_6_2(gcr_bytes),
+        };
+
+        if let Some(sector_data) = sector_data {
+            let dsk_idx = format.logical_sector(sector) * BYTES_PER_SECTOR;
+            dsk[dsk_idx..dsk_idx + BYTES_PER_SECTOR].copy_from_slice(&sector_data);
+            found[sector] = true;
+        }
+    }
+
+    match found.iter().all(|found| *found) {
+        true => Ok(()),
+        false => Err("Disk contains sectors that could not be read."),
+    }
+}
+
+pub fn convert(tracks: &[WozTrack], format: SectorFormat) -> Result<Vec<u8>, &'static str> {
+    if tracks.len() < NUM_TRACKS {
+        return Err("Disk does not contain enough tracks.");
+    }
+
+    let track_size = format.num_sectors() as usize * BYTES_PER_SECTOR;
+    let mut dsk = vec![0; format.image_size()];
+
+    for (i, dsk_track) in dsk.chunks_mut(track_size).enumerate() {
+        convert_track(&tracks[i], dsk_track, i as u8, format)?;
+    }
+
+    Ok(dsk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wizard_of_woz::WozImage;
+
+    const VOLUME: u8 = 17;
+
+    // Every sector holds different data, so a sector ending up in the wrong place gets noticed
+    fn test_sectors(format: SectorFormat) -> Vec<u8> {
+        (0..format.image_size())
+            .map(|i| (i * 7 + i / 256) as u8)
+            .collect()
+    }
+
+    fn round_trip(format: SectorFormat) {
+        let dsk = test_sectors(format);
+        let woz_image = WozImage::from_sectors(&dsk, format, VOLUME).unwrap();
+
+        assert_eq!(convert(&woz_image.tracks, format).unwrap(), dsk);
+        assert_eq!(read_volume(&woz_image.tracks[0], format), Some(VOLUME));
+    }
+
+    #[test]
+    fn dos33_round_trip() {
+        round_trip(SectorFormat::Dos33);
+    }
+
+    #[test]
+    fn prodos_round_trip() {
+        round_trip(SectorFormat::ProDos);
+    }
+
+    #[test]
+    fn dos32_round_trip() {
+        round_trip(SectorFormat::Dos32);
+    }
+}